@@ -0,0 +1,245 @@
+//! Compares the runtime against the mailbox runtime it replaced by reading
+//! random blocks out of a file on tmpfs so that the numbers are dominated
+//! by the runtime rather than the storage. The results can also be saved as
+//! a baseline and compared against, e.g. one saved before a change.
+//!
+//! cargo bench --bench runtime -- [--save-baseline <name>]
+//!     [--baseline <name>]
+
+#[allow(dead_code)]
+#[path = "../src/runtime.rs"]
+mod runtime;
+
+#[allow(dead_code)]
+#[path = "baseline/runtime.rs"]
+mod baseline;
+
+mod types {
+    pub type Ring = io_uring::IoUring;
+}
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use io_uring::{IoUring, opcode, types::Fd};
+
+const FILE_SIZE: u64 = 64 << 20;
+const BLOCK_SIZE: u32 = 4096;
+const NR_TASKS: u16 = 128;
+const OPS_PER_TASK: u32 = 2000;
+const FAN_OUT: usize = 4;
+const ROUNDS: usize = 5;
+
+type Bench = fn(i32, usize) -> Result<Duration>;
+
+/// Generates a benchmark for the given runtime module. Every task either
+/// submits one read at a time or a batch of `FAN_OUT` reads and awaits
+/// them in order, which is what the IO tasks do with chunk parts.
+macro_rules! bench_runtime {
+    ($name:ident, $new:expr) => {
+        fn $name(fd: i32, fan_out: usize) -> Result<Duration> {
+            let ring = IoUring::new(NR_TASKS as u32 * FAN_OUT as u32)?;
+            let mut runtime = $new(ring);
+
+            for task_id in 0..NR_TASKS {
+                runtime.spawn(task_id, |mut submitter| async move {
+                    let mut buf = vec![0u8; BLOCK_SIZE as usize * fan_out];
+                    let mut seed = task_id as u64 + 1;
+
+                    for _ in 0..OPS_PER_TASK / fan_out as u32 {
+                        let waiters = buf
+                            .chunks_mut(BLOCK_SIZE as usize)
+                            .map(|block| {
+                                let offset = next_offset(&mut seed);
+                                let sqe = opcode::Read::new(
+                                    Fd(fd),
+                                    block.as_mut_ptr(),
+                                    BLOCK_SIZE,
+                                )
+                                .offset(offset)
+                                .build();
+
+                                submitter.submit_entry(sqe).unwrap()
+                            })
+                            .collect::<Vec<_>>();
+
+                        for waiter in waiters {
+                            assert_eq!(waiter.await, BLOCK_SIZE as i32);
+                        }
+                    }
+                });
+            }
+
+            let start = Instant::now();
+            runtime.run()?;
+            Ok(start.elapsed())
+        }
+    };
+}
+
+bench_runtime!(bench_slab, |ring| runtime::Runtime::new(ring, None));
+bench_runtime!(bench_mailbox, baseline::Runtime::new);
+
+fn next_offset(seed: &mut u64) -> u64 {
+    // xorshift64
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+
+    (*seed % (FILE_SIZE / BLOCK_SIZE as u64)) * BLOCK_SIZE as u64
+}
+
+/// The median time per op in nanoseconds.
+fn ns_per_op(times: &mut [Duration]) -> f64 {
+    times.sort();
+
+    let ops = NR_TASKS as u64 * OPS_PER_TASK as u64;
+    times[times.len() / 2].as_nanos() as f64 / ops as f64
+}
+
+fn report(
+    name: &str,
+    fan_out: usize,
+    ns_per_op: f64,
+    baseline: Option<(&str, f64)>,
+) {
+    print!(
+        "{:<8} fan-out {}: {:>8.0} ns/op {:>10.0} ops/s",
+        name,
+        fan_out,
+        ns_per_op,
+        1e9 / ns_per_op
+    );
+
+    match baseline {
+        Some((name, baseline)) => println!(
+            " {:>+7.1}% vs {}",
+            (ns_per_op / baseline - 1.0) * 100.0,
+            name
+        ),
+        None => println!(),
+    }
+}
+
+fn measure(bench: Bench, fd: i32, fan_out: usize) -> Result<f64> {
+    let mut times = (0..ROUNDS)
+        .map(|_| bench(fd, fan_out))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ns_per_op(&mut times))
+}
+
+/// Baselines are kept in the target directory, one `<fan-out> <ns/op>`
+/// line per benchmark of the current runtime.
+fn baseline_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target/bench-baselines")
+        .join(format!("runtime-{}", name))
+}
+
+fn load_baseline(name: &str) -> Result<BTreeMap<usize, f64>> {
+    let path = baseline_path(name);
+    let contents = fs::read_to_string(&path).with_context(|| {
+        format!("Failed to read the baseline {}", path.display())
+    })?;
+
+    let mut baseline = BTreeMap::new();
+    for line in contents.lines() {
+        let Some((fan_out, ns_per_op)) = line.split_once(' ') else {
+            bail!("Invalid line in {}: {}", path.display(), line);
+        };
+        baseline.insert(fan_out.parse()?, ns_per_op.parse()?);
+    }
+
+    Ok(baseline)
+}
+
+fn save_baseline(
+    name: &str,
+    results: &BTreeMap<usize, f64>,
+) -> Result<()> {
+    let path = baseline_path(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let contents: String = results
+        .iter()
+        .map(|(fan_out, ns_per_op)| format!("{} {}\n", fan_out, ns_per_op))
+        .collect();
+
+    fs::write(&path, contents).with_context(|| {
+        format!("Failed to write the baseline {}", path.display())
+    })
+}
+
+fn bench_file() -> PathBuf {
+    let dir = PathBuf::from("/dev/shm");
+    let dir = if dir.is_dir() {
+        dir
+    } else {
+        std::env::temp_dir()
+    };
+
+    dir.join(format!("blkchnkr-bench-{}", std::process::id()))
+}
+
+fn main() -> Result<()> {
+    let mut save = None;
+    let mut compare = None;
+
+    // cargo passes --bench along.
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save-baseline" => save = args.next(),
+            "--baseline" => compare = args.next(),
+            _ => {}
+        }
+    }
+
+    let baseline = compare.as_deref().map(load_baseline).transpose()?;
+
+    let path = bench_file();
+    let file = File::create_new(&path)?;
+    file.set_len(FILE_SIZE)?;
+
+    let fd = file.as_raw_fd();
+    let mut results = BTreeMap::new();
+
+    let result = (|| {
+        for fan_out in [1, FAN_OUT] {
+            let mailbox = measure(bench_mailbox, fd, fan_out)?;
+            report("mailbox", fan_out, mailbox, None);
+
+            let slab = measure(bench_slab, fd, fan_out)?;
+            report("slab", fan_out, slab, Some(("mailbox", mailbox)));
+
+            if let Some(name) = &compare
+                && let Some(base) = baseline
+                    .as_ref()
+                    .and_then(|baseline| baseline.get(&fan_out).copied())
+            {
+                report("slab", fan_out, slab, Some((name, base)));
+            }
+
+            results.insert(fan_out, slab);
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })();
+
+    drop(file);
+    fs::remove_file(&path)?;
+    result?;
+
+    match save {
+        Some(name) => save_baseline(&name, &results),
+        None => Ok(()),
+    }
+}
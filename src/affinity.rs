@@ -0,0 +1,116 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Error, Result, anyhow, bail};
+use nix::sched::CpuSet;
+
+/// A list of CPUs in the usual "0-3,8,10-11" format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(Vec<usize>);
+
+impl CpuList {
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0.binary_search(&cpu).is_ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        self.0.iter().copied()
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = Vec::new();
+
+        for range in s.trim().split(',') {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (range, range),
+            };
+
+            let start: usize = start
+                .trim()
+                .parse()
+                .with_context(|| anyhow!("Invalid CPU \"{}\"", start))?;
+            let end: usize = end
+                .trim()
+                .parse()
+                .with_context(|| anyhow!("Invalid CPU \"{}\"", end))?;
+
+            if start > end || end >= CpuSet::count() {
+                bail!("Invalid CPU range \"{}\"", range);
+            }
+
+            cpus.extend(start..=end);
+        }
+
+        cpus.sort_unstable();
+        cpus.dedup();
+
+        Ok(Self(cpus))
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut iter = self.0.iter().copied().peekable();
+        let mut first = true;
+
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.next_if_eq(&(end + 1)).is_some() {
+                end += 1;
+            }
+
+            if !first {
+                write!(f, ",")?;
+            }
+            first = false;
+
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the set of CPUs a worker should run on out of the queue's CPU
+/// mask as reported by the driver. If the user restricted blkchnkr to a
+/// set of CPUs, only those are used. If none of them is mapped to the
+/// queue, the worker may run on any of the allowed CPUs.
+pub fn cpu_set_for_queue(
+    mask: &[u8],
+    allowed: Option<&CpuList>,
+) -> Option<CpuSet> {
+    let mut set = CpuSet::new();
+    let mut empty = true;
+
+    for (i, byte) in mask.iter().enumerate() {
+        for bit in 0..8 {
+            let cpu = i * 8 + bit;
+
+            if byte & (1 << bit) == 0 || cpu >= CpuSet::count() {
+                continue;
+            }
+
+            if allowed.is_none_or(|allowed| allowed.contains(cpu)) {
+                _ = set.set(cpu);
+                empty = false;
+            }
+        }
+    }
+
+    if empty && let Some(allowed) = allowed {
+        for cpu in allowed.iter() {
+            _ = set.set(cpu);
+            empty = false;
+        }
+    }
+
+    if empty { None } else { Some(set) }
+}
@@ -0,0 +1,104 @@
+use std::os::unix::fs::MetadataExt;
+use std::sync::mpsc::{self, Sender};
+use std::{fmt, fs, str::FromStr, thread};
+
+use anyhow::{Error, Result, bail};
+
+use crate::config::Config;
+use crate::util::{build_filepath, open_or_create_chunk};
+
+/// How the space of a chunk is allocated when the chunk is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Chunks are sparse files, space is allocated as it's written to.
+    Sparse,
+
+    /// Chunks are fully allocated.
+    Preallocate,
+
+    /// Chunks are fully allocated and so are the chunks which follow a
+    /// newly opened one, ahead of them being written to.
+    PreallocateAhead,
+}
+
+impl Allocation {
+    pub fn preallocate(&self) -> bool {
+        *self != Allocation::Sparse
+    }
+}
+
+impl FromStr for Allocation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "sparse" => Ok(Allocation::Sparse),
+            "preallocate" => Ok(Allocation::Preallocate),
+            "preallocate-ahead" => Ok(Allocation::PreallocateAhead),
+            s => bail!("Unknown allocation policy \"{}\"", s),
+        }
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Allocation::Sparse => "sparse",
+            Allocation::Preallocate => "preallocate",
+            Allocation::PreallocateAhead => "preallocate-ahead",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Allocates the chunks following the ones opened by the workers on a
+/// background thread. The thread exits once the preallocator is dropped.
+pub struct Preallocator {
+    sender: Sender<u32>,
+}
+
+impl Preallocator {
+    pub fn new(config: &Config) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<u32>();
+        let config = config.clone();
+
+        thread::Builder::new()
+            .name("blkchnkr-prealloc".into())
+            .spawn(move || {
+                let nr_chunks = config.size.div_ceil(config.chunk_size);
+                let ahead = config.preallocate_ahead() as u64;
+
+                // Workers opening chunks in sequence ask for mostly the
+                // same chunks. Those are allocated already and skipped.
+                for chunk in receiver {
+                    let last = (chunk as u64 + ahead).min(nr_chunks - 1);
+
+                    for next in (chunk as u64 + 1)..=last {
+                        preallocate_chunk(&config, next as u32);
+                    }
+                }
+            })?;
+
+        Ok(Self { sender })
+    }
+
+    /// Called when a chunk is opened.
+    pub fn opened(&self, chunk: u32) {
+        _ = self.sender.send(chunk);
+    }
+}
+
+fn preallocate_chunk(config: &Config, chunk: u32) {
+    let allocated = build_filepath(config, chunk)
+        .and_then(|path| Ok(fs::metadata(path)?))
+        .is_ok_and(|meta| meta.blocks() * 512 >= config.chunk_size);
+
+    if allocated {
+        return;
+    }
+
+    if let Err(err) = open_or_create_chunk(config, chunk) {
+        warn!("Failed to preallocate chunk {} err={}", chunk, err);
+    }
+}
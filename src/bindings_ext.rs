@@ -3,12 +3,15 @@ use std::fmt;
 use nix::{libc, request_code_read, request_code_readwrite};
 
 use crate::bindings::{
-    ublk_params, ublksrv_ctrl_cmd, ublksrv_ctrl_dev_info, ublksrv_io_cmd,
+    UBLK_F_PER_IO_DAEMON, UBLK_F_QUIESCE, UBLK_F_UNPRIVILEGED_DEV,
+    UBLK_IO_OP_DISCARD, UBLK_IO_OP_FLUSH, UBLK_IO_OP_READ,
+    UBLK_IO_OP_WRITE, UBLK_IO_OP_WRITE_ZEROES, ublk_params,
+    ublksrv_ctrl_cmd, ublksrv_ctrl_dev_info, ublksrv_io_cmd,
     ublksrv_io_desc,
 };
 
-pub const UBLK_U_CMD_GET_DEV_INFO: u32 =
-    request_code_read!(b'u', 0x02, size_of::<ublksrv_ctrl_cmd>()) as u32;
+pub const UBLK_U_CMD_GET_QUEUE_AFFINITY: u32 =
+    request_code_read!(b'u', 0x01, size_of::<ublksrv_ctrl_cmd>()) as u32;
 
 pub const UBLK_U_CMD_ADD_DEV: u32 =
     request_code_readwrite!(b'u', 0x04, size_of::<ublksrv_ctrl_cmd>())
@@ -26,6 +29,9 @@ pub const UBLK_U_CMD_SET_PARAMS: u32 =
     request_code_readwrite!(b'u', 0x08, size_of::<ublksrv_ctrl_cmd>())
         as u32;
 
+pub const UBLK_U_CMD_GET_PARAMS: u32 =
+    request_code_read!(b'u', 0x09, size_of::<ublksrv_ctrl_cmd>()) as u32;
+
 pub const UBLK_U_CMD_START_USER_RECOVERY: u32 =
     request_code_readwrite!(b'u', 0x10, size_of::<ublksrv_ctrl_cmd>())
         as u32;
@@ -34,9 +40,16 @@ pub const UBLK_U_CMD_END_USER_RECOVERY: u32 =
     request_code_readwrite!(b'u', 0x11, size_of::<ublksrv_ctrl_cmd>())
         as u32;
 
+pub const UBLK_U_CMD_GET_DEV_INFO2: u32 =
+    request_code_read!(b'u', 0x12, size_of::<ublksrv_ctrl_cmd>()) as u32;
+
 pub const UBLK_U_CMD_DEL_DEV_ASYNC: u32 =
     request_code_read!(b'u', 0x14, size_of::<ublksrv_ctrl_cmd>()) as u32;
 
+pub const UBLK_U_CMD_QUIESCE_DEV: u32 =
+    request_code_readwrite!(b'u', 0x16, size_of::<ublksrv_ctrl_cmd>())
+        as u32;
+
 pub const UBLK_U_IO_FETCH_REQ: u32 =
     request_code_readwrite!(b'u', 0x20, size_of::<ublksrv_io_cmd>())
         as u32;
@@ -51,6 +64,21 @@ impl ublksrv_ctrl_dev_info {
     pub fn len() -> u16 {
         size_of::<ublksrv_ctrl_dev_info>() as u16
     }
+
+    #[inline(always)]
+    pub fn is_unprivileged(&self) -> bool {
+        self.flags & UBLK_F_UNPRIVILEGED_DEV as u64 != 0
+    }
+
+    #[inline(always)]
+    pub fn is_per_io_daemon(&self) -> bool {
+        self.flags & UBLK_F_PER_IO_DAEMON as u64 != 0
+    }
+
+    #[inline(always)]
+    pub fn supports_quiesce(&self) -> bool {
+        self.flags & UBLK_F_QUIESCE as u64 != 0
+    }
 }
 
 impl ublk_params {
@@ -60,6 +88,17 @@ impl ublk_params {
     }
 }
 
+pub fn op_name(op: u32) -> &'static str {
+    match op {
+        UBLK_IO_OP_READ => "read",
+        UBLK_IO_OP_WRITE => "write",
+        UBLK_IO_OP_FLUSH => "flush",
+        UBLK_IO_OP_DISCARD => "discard",
+        UBLK_IO_OP_WRITE_ZEROES => "write zeroes",
+        _ => "unknown",
+    }
+}
+
 impl ublksrv_io_desc {
     #[inline(always)]
     pub fn op(&self) -> u32 {
@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+
+use crate::chunk_registry::ChunkRegistry;
+
+struct Entry {
+    /// The index into the ring's table of registered files.
+    index: u32,
+
+    /// When the chunk was last used, the key into the LRU.
+    tick: u64,
+
+    /// The number of in-flight requests using the chunk. A pinned chunk is
+    /// never evicted as its index must not change under the request.
+    pins: u32,
+
+    /// The tick at which the chunk was last flushed. The chunk has been
+    /// used since unless it's the current one.
+    flushed: u64,
+}
+
+/// A chunk which isn't pinned and has been used since it was last flushed.
+/// It has to be flushed through the ring before it's closed.
+#[derive(Clone, Copy)]
+pub struct Unflushed {
+    pub chunk: u32,
+    pub index: u32,
+    tick: u64,
+}
+
+/// The chunks a worker has registered with its ring. Holds at most
+/// `capacity` of them and evicts the least recently used one which isn't
+/// pinned to make room for another. Freed indexes are reused. The files
+/// themselves are shared with other workers through the registry.
+pub struct ChunkCache {
+    capacity: usize,
+    first_index: u32,
+    registry: Arc<ChunkRegistry>,
+
+    chunks: HashMap<u32, Entry>,
+    lru: BTreeMap<u64, u32>,
+    free: Vec<u32>,
+
+    tick: u64,
+}
+
+impl ChunkCache {
+    /// Indexes are handed out starting at `first_index`, the ones before
+    /// are left alone.
+    pub fn new(
+        capacity: usize,
+        first_index: u32,
+        registry: Arc<ChunkRegistry>,
+    ) -> Self {
+        Self {
+            capacity,
+            first_index,
+            registry,
+
+            chunks: HashMap::with_capacity(capacity.min(4096)),
+            lru: BTreeMap::new(),
+            free: Vec::new(),
+
+            tick: 0,
+        }
+    }
+
+    /// Looks up the chunk and pins it.
+    pub fn get(cache: &Rc<RefCell<Self>>, chunk: u32) -> Option<ChunkRef> {
+        let mut this = cache.borrow_mut();
+
+        let tick = this.next_tick();
+        let entry = this.chunks.get_mut(&chunk)?;
+
+        let prev_tick = entry.tick;
+        let index = entry.index;
+        entry.tick = tick;
+        entry.pins += 1;
+
+        this.lru.remove(&prev_tick);
+        this.lru.insert(tick, chunk);
+
+        Some(ChunkRef {
+            cache: cache.clone(),
+            chunk,
+            index,
+        })
+    }
+
+    /// Assigns the chunk an index and pins it. Takes over the caller's
+    /// reference to the chunk in the registry. The chunk's fd is to be
+    /// registered at the index, replacing the chunk evicted to make room
+    /// if there was one.
+    pub fn insert(
+        cache: &Rc<RefCell<Self>>,
+        chunk: u32,
+    ) -> Result<ChunkRef> {
+        let mut this = cache.borrow_mut();
+
+        debug_assert!(!this.chunks.contains_key(&chunk));
+
+        let index = if this.chunks.len() < this.capacity {
+            this.free.pop().unwrap_or_else(|| {
+                this.first_index + this.chunks.len() as u32
+            })
+        } else {
+            match this.evict() {
+                Ok(index) => index,
+                Err(err) => {
+                    this.registry.release(chunk);
+                    return Err(err);
+                }
+            }
+        };
+
+        let tick = this.next_tick();
+        this.chunks.insert(
+            chunk,
+            Entry {
+                index,
+                tick,
+                pins: 1,
+                flushed: 0,
+            },
+        );
+        this.lru.insert(tick, chunk);
+
+        Ok(ChunkRef {
+            cache: cache.clone(),
+            chunk,
+            index,
+        })
+    }
+
+    /// Forgets the chunk, e.g. when it couldn't be registered. Its index
+    /// is reused.
+    pub fn remove(&mut self, chunk: u32) {
+        if let Some(entry) = self.chunks.remove(&chunk) {
+            self.lru.remove(&entry.tick);
+            self.free.push(entry.index);
+            self.registry.release(chunk);
+        }
+    }
+
+    /// The chunks which aren't pinned and have been used since they were
+    /// last flushed.
+    pub fn unflushed_idle(&self) -> Vec<Unflushed> {
+        self.chunks
+            .iter()
+            .filter(|(_, entry)| {
+                entry.pins == 0 && entry.flushed != entry.tick
+            })
+            .map(|(&chunk, entry)| Unflushed {
+                chunk,
+                index: entry.index,
+                tick: entry.tick,
+            })
+            .collect()
+    }
+
+    /// The chunk which is to be evicted to make room for another one if
+    /// it has to be flushed first.
+    pub fn unflushed_victim(&self) -> Option<Unflushed> {
+        if self.chunks.len() < self.capacity {
+            return None;
+        }
+
+        let (chunk, entry) = self.victim()?;
+        if entry.flushed == entry.tick {
+            return None;
+        }
+
+        Some(Unflushed {
+            chunk,
+            index: entry.index,
+            tick: entry.tick,
+        })
+    }
+
+    /// Marks the chunk as flushed unless it has been used since the flush
+    /// was submitted.
+    pub fn flushed(&mut self, unflushed: Unflushed) {
+        if let Some(entry) = self.chunks.get_mut(&unflushed.chunk)
+            && entry.tick == unflushed.tick
+        {
+            entry.flushed = entry.tick;
+        }
+    }
+
+    /// Forgets all chunks which aren't pinned and have been flushed since
+    /// they were last used. Returns their indexes, which are to be cleared
+    /// in the ring's table so that the files are closed.
+    pub fn close_idle(&mut self) -> Vec<u32> {
+        let idle: Vec<u32> = self
+            .chunks
+            .iter()
+            .filter(|(_, entry)| {
+                entry.pins == 0 && entry.flushed == entry.tick
+            })
+            .map(|(chunk, _)| *chunk)
+            .collect();
+
+        idle.into_iter()
+            .map(|chunk| {
+                let entry = self.chunks.remove(&chunk).unwrap();
+                self.lru.remove(&entry.tick);
+                self.free.push(entry.index);
+                self.registry.release(chunk);
+                entry.index
+            })
+            .collect()
+    }
+
+    /// The indexes of all cached chunks.
+    pub fn indexes(&self) -> Vec<u32> {
+        self.chunks.values().map(|entry| entry.index).collect()
+    }
+
+    /// Evicts the least recently used chunk which isn't pinned and returns
+    /// its index. The chunk must have been flushed since it was last used,
+    /// see `unflushed_victim`.
+    fn evict(&mut self) -> Result<u32> {
+        let Some((chunk, _)) = self.victim() else {
+            bail!(
+                "All {} open chunks are in use by requests. Raise \
+                max-open-chunks.",
+                self.capacity
+            );
+        };
+
+        let entry = self.chunks.remove(&chunk).unwrap();
+        self.lru.remove(&entry.tick);
+        self.registry.release(chunk);
+
+        debug!("evicted chunk {}", chunk);
+
+        Ok(entry.index)
+    }
+
+    /// The least recently used chunk which isn't pinned.
+    fn victim(&self) -> Option<(u32, &Entry)> {
+        self.lru
+            .values()
+            .map(|chunk| (*chunk, &self.chunks[chunk]))
+            .find(|(_, entry)| entry.pins == 0)
+    }
+
+    fn unpin(&mut self, chunk: u32) {
+        if let Some(entry) = self.chunks.get_mut(&chunk) {
+            entry.pins -= 1;
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// A pinned chunk, unpinned when dropped.
+pub struct ChunkRef {
+    cache: Rc<RefCell<ChunkCache>>,
+    chunk: u32,
+    index: u32,
+}
+
+impl ChunkRef {
+    #[inline(always)]
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+impl Drop for ChunkRef {
+    fn drop(&mut self) {
+        self.cache.borrow_mut().unpin(self.chunk);
+    }
+}
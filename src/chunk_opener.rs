@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll, Waker};
+
+use anyhow::{Context, Result, bail};
+use io_uring::opcode::{Fallocate, Ftruncate, MkDirAt, OpenAt};
+use io_uring::{Probe, squeue, types};
+use nix::{errno::Errno, libc};
+
+use crate::chunk_registry::ChunkRegistry;
+use crate::config::Config;
+use crate::metrics::WorkerMetrics;
+use crate::runtime::Submitter;
+use crate::types::Ring;
+use crate::util::{allocate, chunk_path, open_or_create_chunk};
+
+/// Opens (or creates) chunks through the worker's ring so that the tasks
+/// aren't blocked on the filesystem. Falls back to the blocking calls if
+/// the kernel doesn't support the operations. Tasks which need a chunk
+/// another task is already opening wait for it instead of opening it
+/// again.
+pub struct ChunkOpener {
+    config: Config,
+    chunks_dir: PathBuf,
+    registry: Arc<ChunkRegistry>,
+    metrics: Arc<WorkerMetrics>,
+
+    mkdirat: bool,
+    openat: bool,
+    fallocate: bool,
+    ftruncate: bool,
+
+    /// The chunks being opened and the tasks waiting for them.
+    pending: RefCell<HashMap<u32, Vec<Waker>>>,
+}
+
+impl ChunkOpener {
+    pub fn new(
+        config: &Config,
+        ring: &Ring,
+        registry: Arc<ChunkRegistry>,
+        metrics: Arc<WorkerMetrics>,
+    ) -> Result<Self> {
+        let mut chunks_dir = fs::canonicalize(&config.repository)
+            .context("Failed to resolve the repository path.")?;
+        chunks_dir.push("chunks");
+
+        // An unsupported probe leaves all of the operations unsupported.
+        let mut probe = Probe::new();
+        _ = ring.submitter().register_probe(&mut probe);
+
+        Ok(Self {
+            config: config.clone(),
+            chunks_dir,
+            registry,
+            metrics,
+
+            mkdirat: probe.is_supported(MkDirAt::CODE),
+            openat: probe.is_supported(OpenAt::CODE),
+            fallocate: probe.is_supported(Fallocate::CODE),
+            ftruncate: probe.is_supported(Ftruncate::CODE),
+
+            pending: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn path(&self, file_num: u32) -> PathBuf {
+        chunk_path(&self.chunks_dir, file_num)
+    }
+
+    pub fn is_opening(&self, file_num: u32) -> bool {
+        self.pending.borrow().contains_key(&file_num)
+    }
+
+    /// Resolves once the chunk is no longer being opened, successfully or
+    /// not.
+    pub fn opened(&self, file_num: u32) -> Opened<'_> {
+        Opened {
+            opener: self,
+            file_num,
+        }
+    }
+
+    /// Marks the chunk as being opened until the returned guard is
+    /// dropped.
+    pub fn begin(&self, file_num: u32) -> Opening<'_> {
+        let prev = self.pending.borrow_mut().insert(file_num, Vec::new());
+        debug_assert!(prev.is_none());
+
+        Opening {
+            opener: self,
+            file_num,
+        }
+    }
+
+    /// Returns the chunk's fd, opening (or creating) the chunk first if no
+    /// worker has it open yet. Must be paired with a release in the
+    /// registry.
+    pub async fn acquire(
+        &self,
+        submitter: &mut Submitter,
+        file_num: u32,
+    ) -> Result<RawFd> {
+        if let Some(fd) = self.registry.acquire(file_num) {
+            return Ok(fd);
+        }
+
+        let file = if self.mkdirat && self.openat {
+            self.open_or_create(submitter, file_num).await?
+        } else {
+            let created = !self.path(file_num).exists();
+            let file = open_or_create_chunk(&self.config, file_num)?;
+            self.metrics.chunk_opened(created);
+            file
+        };
+
+        Ok(self.registry.insert(file_num, file))
+    }
+
+    // All operations are idempotent as workers might race to open the same
+    // chunk.
+    async fn open_or_create(
+        &self,
+        submitter: &mut Submitter,
+        file_num: u32,
+    ) -> Result<File> {
+        let path = self.path(file_num);
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+        let mut flags = libc::O_RDWR | libc::O_CLOEXEC;
+        if self.config.direct_io() {
+            flags |= libc::O_DIRECT;
+        }
+
+        let open = |flags| {
+            OpenAt::new(types::Fd(libc::AT_FDCWD), c_path.as_ptr())
+                .flags(flags)
+                .mode(0o666)
+                .build()
+        };
+
+        let mut result = submit(submitter, open(flags)).await?;
+        let created = result == -libc::ENOENT;
+
+        // The chunk doesn't exist yet.
+        if created {
+            result =
+                submit(submitter, open(flags | libc::O_CREAT)).await?;
+        }
+
+        // Neither does its subdirectory.
+        if created && result == -libc::ENOENT {
+            let dir = path.parent().unwrap();
+            let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+            let mkdir =
+                MkDirAt::new(types::Fd(libc::AT_FDCWD), c_dir.as_ptr())
+                    .mode(0o777)
+                    .build();
+
+            let res = submit(submitter, mkdir).await?;
+            if res < 0 && res != -libc::EEXIST {
+                bail!(
+                    "Failed to create a directory for a chunk: {}",
+                    Errno::from_raw(-res)
+                );
+            }
+
+            result =
+                submit(submitter, open(flags | libc::O_CREAT)).await?;
+        }
+
+        if result < 0 {
+            bail!(
+                "Failed to open/create chunk: {}",
+                Errno::from_raw(-result)
+            );
+        }
+
+        let file = unsafe { File::from_raw_fd(result) };
+        self.metrics.chunk_opened(created);
+        let fd = types::Fd(result);
+        let size = self.config.chunk_size;
+
+        let mut result = -libc::EOPNOTSUPP;
+
+        if self.config.allocation().preallocate() && self.fallocate {
+            let entry = Fallocate::new(fd, size).build();
+            result = submit(submitter, entry).await?;
+        }
+
+        // Sparse chunks, or the filesystem cannot preallocate.
+        if result == -libc::EOPNOTSUPP && self.ftruncate {
+            let entry = Ftruncate::new(fd, size).build();
+            result = submit(submitter, entry).await?;
+        }
+
+        if result == -libc::EOPNOTSUPP {
+            allocate(&self.config, &file)?;
+        } else if result < 0 {
+            bail!(
+                "Failed to allocate chunk: {}",
+                Errno::from_raw(-result)
+            );
+        }
+
+        Ok(file)
+    }
+
+    fn finish(&self, file_num: u32) {
+        let waiters = self.pending.borrow_mut().remove(&file_num);
+
+        for waker in waiters.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+/// Submits the entry until it's not interrupted.
+async fn submit(
+    submitter: &mut Submitter,
+    entry: squeue::Entry,
+) -> Result<i32> {
+    loop {
+        let result = submitter.submit_entry(entry.clone())?.await;
+
+        if result != -libc::EINTR {
+            return Ok(result);
+        }
+    }
+}
+
+/// Wakes up the tasks waiting for the chunk when dropped.
+pub struct Opening<'a> {
+    opener: &'a ChunkOpener,
+    file_num: u32,
+}
+
+impl Drop for Opening<'_> {
+    fn drop(&mut self) {
+        self.opener.finish(self.file_num);
+    }
+}
+
+pub struct Opened<'a> {
+    opener: &'a ChunkOpener,
+    file_num: u32,
+}
+
+impl Future for Opened<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+        let mut pending = self.opener.pending.borrow_mut();
+
+        // The task might be woken up by its other operations so this isn't
+        // necessarily the first poll.
+        match pending.get_mut(&self.file_num) {
+            Some(waiters) => {
+                if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                    waiters.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            None => Poll::Ready(()),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Result;
+
+use crate::allocation::{Allocation, Preallocator};
+use crate::config::Config;
+
+struct SharedChunk {
+    file: File,
+
+    /// The number of workers which have the chunk registered.
+    refs: u32,
+}
+
+/// Chunk files shared by all workers. Each chunk is opened once and stays
+/// open for as long as at least one worker has it registered with its
+/// ring.
+pub struct ChunkRegistry {
+    chunks: Mutex<HashMap<u32, SharedChunk>>,
+    preallocator: Option<Preallocator>,
+}
+
+impl ChunkRegistry {
+    pub fn new(config: &Config) -> Result<Self> {
+        let preallocator = match config.allocation() {
+            Allocation::PreallocateAhead => {
+                Some(Preallocator::new(config)?)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            chunks: Mutex::new(HashMap::new()),
+            preallocator,
+        })
+    }
+
+    /// Returns the chunk's fd if another worker has it open. Must be
+    /// paired with `release`.
+    pub fn acquire(&self, file_num: u32) -> Option<RawFd> {
+        let mut chunks = self.lock();
+        let chunk = chunks.get_mut(&file_num)?;
+
+        chunk.refs += 1;
+        Some(chunk.file.as_raw_fd())
+    }
+
+    /// Adds a chunk the caller has just opened and returns its fd. Must be
+    /// paired with `release`.
+    pub fn insert(&self, file_num: u32, file: File) -> RawFd {
+        // Opening a chunk takes a while so it's done without holding up
+        // the other workers. If another worker opened the same chunk
+        // meanwhile, its file wins and ours is closed.
+        let mut chunks = self.lock();
+        let chunk = match chunks.entry(file_num) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                if let Some(preallocator) = &self.preallocator {
+                    preallocator.opened(file_num);
+                }
+
+                entry.insert(SharedChunk { file, refs: 0 })
+            }
+        };
+
+        chunk.refs += 1;
+        chunk.file.as_raw_fd()
+    }
+
+    /// Closes the chunk once no worker has it registered. The workers
+    /// flush a chunk through their rings before they let go of it.
+    pub fn release(&self, file_num: u32) {
+        let mut chunks = self.lock();
+
+        if let Entry::Occupied(mut entry) = chunks.entry(file_num) {
+            entry.get_mut().refs -= 1;
+
+            if entry.get().refs == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// The number of chunks open across all workers.
+    pub fn nr_open(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, SharedChunk>> {
+        // The map is consistent even if a worker panicked while holding
+        // the lock.
+        self.chunks.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
@@ -1,6 +1,7 @@
 use std::env::Args;
 use std::path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -8,6 +9,7 @@ use anyhow::anyhow;
 use anyhow::bail;
 
 use crate::config::Config;
+use crate::log::{Format, Level, Target};
 
 pub const HELP: &str = "
 blkchnkr is a utility for creating virtual block devices backed by on
@@ -47,6 +49,70 @@ Available commands:
 
     start       Starts the server at the given path (--repository or -r).
 
+                Running without CAP_SYS_ADMIN creates an unprivileged device
+                which requires appropriate udev rules.
+
+                The log level can be set via --log-level (error, warn, info
+                or debug) and defaults to info. Messages go to stderr unless
+                --log-target is set to stdout, journald or syslog.
+                --log-format json writes one JSON object per line. The
+                defaults can also be set via BLKCHNKR_LOG_LEVEL,
+                BLKCHNKR_LOG_TARGET and BLKCHNKR_LOG_FORMAT.
+
+                Every request and its result can be recorded to a file via
+                --trace. The file holds at most --trace-size bytes (64M by
+                default) after which the oldest requests are overwritten.
+                Supported suffixes: M, G, T.
+
+                With --daemon, the server forks into the background and
+                the command exits once the device is up, with a non-zero
+                status if it failed to come up. The pid is written to
+                blkchnkr.pid in the repository. Messages go to the file
+                given via --log-file, or to syslog.
+
+    quiesce     Quiesces the device of the server running at the given
+                path (--repository or -r). In-flight IO is drained, all
+                open chunks are synced and new IO is held until the device
+                is resumed. The chunks directory can be safely copied in
+                the meantime. The repository must have a fixed --dev-id.
+
+    resume      Resumes the quiesced device of the server running at the
+                given path (--repository or -r).
+
+    stats       Prints the latency percentiles of every request type of
+                the server running at the given path (--repository or -r),
+                split into the time spent on the backing store and the
+                rest.
+
+    control     Sends a command to the server running at the given path
+                (--repository or -r) over its control socket and prints
+                the output. Available commands:
+
+                status                  The state of the device.
+                stats                   Same as the stats command.
+                set-log-level <level>   One of error, warn, info, debug.
+                flush                   Syncs all open chunks.
+                close-idle-chunks       Closes the chunks no request is
+                                        currently using.
+                quiesce, resume         Same as the respective commands.
+                stop                    Stops the server.
+
+    heatmap     Prints the most accessed chunks of the given repository
+                (--repository or -r) and a map of the accesses across the
+                whole device. The number of chunks listed can be set via
+                --top and defaults to 10. A running server persists the
+                accesses every minute and when it stops.
+
+    trace dump  Prints the requests recorded in the given trace file as
+                text, or as CSV with --csv.
+
+    replay      Replays the requests recorded in a trace (--trace) against
+                the given repository (--repository or -r) without a device
+                and prints the latency percentiles. The requests keep their
+                original timing unless --fast is given, at most --depth
+                (128 by default) are in flight at once. Writes overwrite
+                the data in the repository. The server must not be running.
+
     expand      Expand the size of the device of the given repository
                 (--repository or -r) and round up the new size to the
                 nearest multiple of the chunk size.
@@ -80,14 +146,136 @@ impl Init {
 #[derive(Debug)]
 pub struct Start {
     pub repository: PathBuf,
+    pub log_level: Option<Level>,
+    pub log_target: Option<Target>,
+    pub log_format: Option<Format>,
+    pub trace: Option<PathBuf>,
+    pub trace_size: u64,
+    pub daemon: bool,
+    pub log_file: Option<PathBuf>,
 }
 
 impl Start {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        repository: PathBuf,
+        log_level: Option<Level>,
+        log_target: Option<Target>,
+        log_format: Option<Format>,
+        trace: Option<PathBuf>,
+        trace_size: u64,
+        daemon: bool,
+        log_file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            repository,
+            log_level,
+            log_target,
+            log_format,
+            trace,
+            trace_size,
+            daemon,
+            log_file,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Quiesce {
+    pub repository: PathBuf,
+}
+
+impl Quiesce {
+    pub fn new(repository: PathBuf) -> Self {
+        Self { repository }
+    }
+}
+
+#[derive(Debug)]
+pub struct Resume {
+    pub repository: PathBuf,
+}
+
+impl Resume {
+    pub fn new(repository: PathBuf) -> Self {
+        Self { repository }
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub repository: PathBuf,
+}
+
+impl Stats {
     pub fn new(repository: PathBuf) -> Self {
         Self { repository }
     }
 }
 
+#[derive(Debug)]
+pub struct Control {
+    pub repository: PathBuf,
+    pub command: String,
+}
+
+impl Control {
+    pub fn new(repository: PathBuf, command: String) -> Self {
+        Self {
+            repository,
+            command,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Heatmap {
+    pub repository: PathBuf,
+    pub top: usize,
+}
+
+impl Heatmap {
+    pub fn new(repository: PathBuf, top: usize) -> Self {
+        Self { repository, top }
+    }
+}
+
+#[derive(Debug)]
+pub struct TraceDump {
+    pub path: PathBuf,
+    pub csv: bool,
+}
+
+impl TraceDump {
+    pub fn new(path: PathBuf, csv: bool) -> Self {
+        Self { path, csv }
+    }
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    pub repository: PathBuf,
+    pub trace: PathBuf,
+    pub fast: bool,
+    pub depth: u16,
+}
+
+impl Replay {
+    pub fn new(
+        repository: PathBuf,
+        trace: PathBuf,
+        fast: bool,
+        depth: u16,
+    ) -> Self {
+        Self {
+            repository,
+            trace,
+            fast,
+            depth,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Expand {
     pub repository: PathBuf,
@@ -106,6 +294,13 @@ pub enum Command {
     Help(Help),
     Init(Init),
     Start(Start),
+    Quiesce(Quiesce),
+    Resume(Resume),
+    Stats(Stats),
+    Control(Control),
+    Heatmap(Heatmap),
+    TraceDump(TraceDump),
+    Replay(Replay),
     Expand(Expand),
 }
 
@@ -122,6 +317,13 @@ pub fn parse_cli(env: Args) -> Result<Command> {
         Some("--help") | Some("-h") => Ok(Command::Help(Help)),
         Some("init") => parse_init(env),
         Some("start") => parse_start(env),
+        Some("quiesce") => parse_quiesce(env),
+        Some("resume") => parse_resume(env),
+        Some("stats") => parse_stats(env),
+        Some("control") => parse_control(env),
+        Some("heatmap") => parse_heatmap(env),
+        Some("trace") => parse_trace(env),
+        Some("replay") => parse_replay(env),
         Some("expand") => parse_expand(env),
         _ => {
             bail!("A valid command is required. See --help.")
@@ -210,6 +412,95 @@ fn parse_init(mut env: impl Iterator<Item = String>) -> Result<Command> {
 
 fn parse_start(mut env: impl Iterator<Item = String>) -> Result<Command> {
     let mut repository: Option<PathBuf> = None;
+    let mut log_level: Option<Level> = None;
+    let mut log_target: Option<Target> = None;
+    let mut log_format: Option<Format> = None;
+    let mut trace: Option<PathBuf> = None;
+    let mut trace_size: u64 = 64 * 1024 * 1024;
+    let mut daemon = false;
+    let mut log_file: Option<PathBuf> = None;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some("--log-level") => {
+                log_level = Some(parse_value("--log-level", env.next())?);
+            }
+            Some("--log-target") => {
+                log_target =
+                    Some(parse_value("--log-target", env.next())?);
+            }
+            Some("--log-format") => {
+                log_format =
+                    Some(parse_value("--log-format", env.next())?);
+            }
+            Some("--trace") => {
+                trace = Some(parse_path("--trace", env.next())?);
+            }
+            Some("--trace-size") => {
+                trace_size = parse_size("--trace-size", env.next())?;
+            }
+            Some("--daemon") => {
+                daemon = true;
+            }
+            Some("--log-file") => {
+                log_file = Some(parse_path("--log-file", env.next())?);
+            }
+            Some(f) => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    Ok(Command::Start(Start::new(
+        repository, log_level, log_target, log_format, trace, trace_size,
+        daemon, log_file,
+    )))
+}
+
+fn parse_quiesce(
+    mut env: impl Iterator<Item = String>,
+) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some(f) => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    Ok(Command::Quiesce(Quiesce::new(repository)))
+}
+
+fn parse_resume(mut env: impl Iterator<Item = String>) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
 
     loop {
         match env.next().as_deref() {
@@ -232,7 +523,188 @@ fn parse_start(mut env: impl Iterator<Item = String>) -> Result<Command> {
         );
     };
 
-    Ok(Command::Start(Start::new(repository)))
+    Ok(Command::Resume(Resume::new(repository)))
+}
+
+fn parse_stats(mut env: impl Iterator<Item = String>) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some(f) => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    Ok(Command::Stats(Stats::new(repository)))
+}
+
+fn parse_control(
+    mut env: impl Iterator<Item = String>,
+) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
+    let mut words: Vec<String> = Vec::new();
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some(f) if f.starts_with('-') => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            Some(word) => {
+                words.push(word.to_owned());
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    if words.is_empty() {
+        bail!("The command is required. See --help.");
+    }
+
+    Ok(Command::Control(Control::new(repository, words.join(" "))))
+}
+
+fn parse_heatmap(
+    mut env: impl Iterator<Item = String>,
+) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
+    let mut top: usize = 10;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some("--top") => {
+                top = parse_num("--top", env.next())? as _;
+            }
+            Some(f) => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    Ok(Command::Heatmap(Heatmap::new(repository, top)))
+}
+
+fn parse_trace(mut env: impl Iterator<Item = String>) -> Result<Command> {
+    match env.next().as_deref() {
+        Some("dump") => parse_trace_dump(env),
+        Some("--help") | Some("-h") => Ok(Command::Help(Help)),
+        _ => bail!("A valid trace command is required. See --help."),
+    }
+}
+
+fn parse_trace_dump(
+    mut env: impl Iterator<Item = String>,
+) -> Result<Command> {
+    let mut path: Option<PathBuf> = None;
+    let mut csv = false;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--csv") => {
+                csv = true;
+            }
+            Some(f) if f.starts_with('-') => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            Some(p) => {
+                path = Some(parse_path("trace", Some(p.to_owned()))?);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(path) = path else {
+        bail!("The path to the trace is required. See --help.");
+    };
+
+    Ok(Command::TraceDump(TraceDump::new(path, csv)))
+}
+
+fn parse_replay(mut env: impl Iterator<Item = String>) -> Result<Command> {
+    let mut repository: Option<PathBuf> = None;
+    let mut trace: Option<PathBuf> = None;
+    let mut fast = false;
+    let mut depth: u16 = 128;
+
+    loop {
+        match env.next().as_deref() {
+            Some("--help") | Some("-h") => return Ok(Command::Help(Help)),
+            Some("--repository") | Some("-r") => {
+                repository = Some(parse_path("--repository", env.next())?);
+            }
+            Some("--trace") => {
+                trace = Some(parse_path("--trace", env.next())?);
+            }
+            Some("--fast") => {
+                fast = true;
+            }
+            Some("--depth") => {
+                depth = parse_num("--depth", env.next())?
+                    .try_into()
+                    .context("The --depth value is too large.")?;
+            }
+            Some(f) => {
+                bail!("Unknown flag {}. See --help.", f);
+            }
+            None => {
+                break;
+            }
+        };
+    }
+
+    let Some(repository) = repository else {
+        bail!(
+            "The path to the repository (--repository) is required. See --help."
+        );
+    };
+
+    let Some(trace) = trace else {
+        bail!("The path to the trace (--trace) is required. See --help.");
+    };
+
+    Ok(Command::Replay(Replay::new(repository, trace, fast, depth)))
 }
 
 fn parse_expand(mut env: impl Iterator<Item = String>) -> Result<Command> {
@@ -270,6 +742,18 @@ fn parse_expand(mut env: impl Iterator<Item = String>) -> Result<Command> {
     Ok(Command::Expand(Expand::new(repository, bytes)))
 }
 
+fn parse_value<T>(label: &str, val: Option<String>) -> Result<T>
+where
+    T: FromStr<Err = anyhow::Error>,
+{
+    let Some(val) = val else {
+        bail!("Missing {} value.", label);
+    };
+
+    val.parse()
+        .with_context(|| anyhow!("Invalid {} value.", label))
+}
+
 fn parse_path(label: &str, val: Option<String>) -> Result<PathBuf> {
     let Some(val) = val else {
         bail!("Missing {} value.", label);
@@ -1,5 +1,12 @@
+pub mod control;
 pub mod expand;
+pub mod heatmap;
 pub mod help;
 pub mod init;
+pub mod quiesce;
+pub mod replay;
+pub mod resume;
 pub mod start;
+pub mod stats;
+pub mod trace;
 pub mod version;
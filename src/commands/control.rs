@@ -0,0 +1,13 @@
+use anyhow::Result;
+
+use crate::cli::Control;
+use crate::config::Config;
+use crate::control::send;
+
+pub fn run(control: Control) -> Result<()> {
+    let config = Config::from_repository(control.repository)?;
+
+    print!("{}", send(&config, &control.command)?);
+
+    Ok(())
+}
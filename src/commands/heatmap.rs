@@ -0,0 +1,14 @@
+use anyhow::Result;
+
+use crate::cli::Heatmap as HeatmapCommand;
+use crate::config::Config;
+use crate::heatmap::Heatmap;
+
+pub fn run(command: HeatmapCommand) -> Result<()> {
+    let config = Config::from_repository(command.repository)?;
+    let heatmap = Heatmap::load(&config)?;
+
+    print!("{}", heatmap.render(&config, command.top));
+
+    Ok(())
+}
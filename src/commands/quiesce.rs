@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::bindings::{UBLK_S_DEV_LIVE, UBLK_S_DEV_QUIESCED};
+use crate::cli::Quiesce;
+use crate::config::Config;
+use crate::ctrl::dev_info_for;
+use crate::util::wait_until;
+
+pub fn run(quiesce: Quiesce) -> Result<()> {
+    let config = Config::from_repository(quiesce.repository)?;
+    let dev_info = dev_info_for(&config)?;
+
+    match dev_info.state as u32 {
+        UBLK_S_DEV_LIVE => {}
+        UBLK_S_DEV_QUIESCED if config.quiesced_path().exists() => {
+            info!("The device is already quiesced.");
+            return Ok(());
+        }
+        _ => bail!("The device is not running."),
+    }
+
+    signal::kill(Pid::from_raw(dev_info.ublksrv_pid), Signal::SIGUSR1)?;
+
+    if !wait_until(Duration::from_secs(60), || {
+        config.quiesced_path().exists()
+    }) {
+        bail!("The server didn't quiesce the device in time.");
+    }
+
+    info!(
+        "Quiesced the device at /dev/ublkb{}. IO is held until resumed.",
+        dev_info.dev_id
+    );
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Result, bail};
+
+use crate::chunk_registry::ChunkRegistry;
+use crate::cli::Replay as ReplayCommand;
+use crate::config::Config;
+use crate::io_worker::{IoWorker, MAX_SLOTS};
+use crate::metrics::Metrics;
+use crate::replay::Replay;
+use crate::trace::read_trace;
+
+pub fn run(command: ReplayCommand) -> Result<()> {
+    let config = Config::from_repository(command.repository)?;
+
+    // The server mustn't touch the chunks meanwhile.
+    let _lock = config.lock()?;
+
+    if command.depth == 0 || command.depth as usize > MAX_SLOTS {
+        bail!("The --depth value must be between 1 and {}.", MAX_SLOTS);
+    }
+
+    let (_, mut records) = read_trace(&command.trace)?;
+    let total = records.len();
+
+    // Requests are recorded once they complete and every worker writes its
+    // own batches, the trace isn't in the order the requests arrived in.
+    records.sort_by_key(|record| record.time);
+
+    // The trace might come from a larger device.
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|record| {
+            (record.start_sector + record.nr_sectors as u64) << 9
+                <= config.size
+        })
+        .collect();
+    let skipped = total - records.len();
+
+    if skipped > 0 {
+        warn!(
+            "Skipping {} requests beyond the end of the device",
+            skipped
+        );
+    }
+
+    let max_io_buf_bytes = records
+        .iter()
+        .map(|record| record.nr_sectors << 9)
+        .max()
+        .unwrap_or(0)
+        .max(4096);
+
+    let metrics = Arc::new(Metrics::new(&config, 1));
+    let registry = Arc::new(ChunkRegistry::new(&config)?);
+    let replay = Rc::new(Replay::new(records, !command.fast));
+
+    let started = Instant::now();
+    let mut worker = IoWorker::replay(
+        config,
+        command.depth,
+        max_io_buf_bytes,
+        registry,
+        metrics.register_worker(),
+        replay.clone(),
+    )?;
+    worker.work()?;
+    let elapsed = started.elapsed();
+
+    println!(
+        "Replayed {} requests ({} failed, {} skipped) in {:.2}s, {:.0} \
+        IOPS\n",
+        replay.replayed(),
+        replay.failed(),
+        skipped,
+        elapsed.as_secs_f64(),
+        replay.replayed() as f64 / elapsed.as_secs_f64()
+    );
+    print!("{}", metrics.render_stats());
+
+    Ok(())
+}
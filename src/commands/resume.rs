@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::cli::Resume;
+use crate::config::Config;
+use crate::ctrl::dev_info_for;
+use crate::util::wait_until;
+
+pub fn run(resume: Resume) -> Result<()> {
+    let config = Config::from_repository(resume.repository)?;
+
+    if !config.quiesced_path().exists() {
+        bail!("The device is not quiesced.");
+    }
+
+    let dev_info = dev_info_for(&config)?;
+
+    signal::kill(Pid::from_raw(dev_info.ublksrv_pid), Signal::SIGUSR2)?;
+
+    if !wait_until(Duration::from_secs(60), || {
+        !config.quiesced_path().exists()
+    }) {
+        bail!("The server didn't resume the device in time.");
+    }
+
+    info!("Resumed the device at /dev/ublkb{}.", dev_info.dev_id);
+
+    Ok(())
+}
@@ -1,60 +1,76 @@
-use std::fs::OpenOptions;
+use std::fs::{self, File, OpenOptions};
 use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::process;
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread::{self, JoinHandle, sleep};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, io};
 
 use anyhow::{Context, Result, bail};
 use caps::{CapSet, Capability};
-use io_uring::opcode::PollAdd;
-use io_uring::types::{Fd, Fixed};
+use io_uring::opcode::{AsyncCancel, PollAdd, Timeout};
+use io_uring::types::{Fd, Timespec};
 use nix::libc;
+use nix::sched::{CpuSet, sched_setaffinity};
 use nix::sys::resource::{self, Resource};
 use nix::sys::signal::{self, SigSet, SigmaskHow, Signal};
 use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::Pid;
 
+use crate::affinity::cpu_set_for_queue;
 use crate::bindings::{
-    UBLK_ATTR_FUA, UBLK_ATTR_VOLATILE_CACHE, UBLK_F_USER_RECOVERY,
-    UBLK_PARAM_TYPE_DMA_ALIGN, UBLK_S_DEV_DEAD, UBLK_S_DEV_FAIL_IO,
-    UBLK_S_DEV_LIVE, UBLK_S_DEV_QUIESCED, ublk_param_dma_align,
+    UBLK_ATTR_FUA, UBLK_ATTR_ROTATIONAL, UBLK_ATTR_VOLATILE_CACHE,
+    UBLK_F_PER_IO_DAEMON, UBLK_F_QUIESCE, UBLK_F_UNPRIVILEGED_DEV,
+    UBLK_F_USER_RECOVERY, UBLK_PARAM_TYPE_DMA_ALIGN, UBLK_S_DEV_DEAD,
+    UBLK_S_DEV_FAIL_IO, UBLK_S_DEV_LIVE, UBLK_S_DEV_QUIESCED,
+    ublk_param_dma_align,
 };
 use crate::bindings::{
-    UBLK_PARAM_TYPE_BASIC, ublk_param_basic, ublk_params,
-    ublksrv_ctrl_dev_info,
+    UBLK_PARAM_TYPE_BASIC, UBLK_PARAM_TYPE_SEGMENT, ublk_param_basic,
+    ublk_param_segment, ublk_params, ublksrv_ctrl_dev_info,
 };
+use crate::chunk_registry::ChunkRegistry;
 use crate::config::Config;
-use crate::io_worker::IoWorker;
+use crate::control::{
+    self, ControlSocket, Request, WorkerCommand, WorkerControl,
+    WorkerInbox, worker_channel,
+};
+use crate::daemon::{self, Daemon};
+use crate::heatmap::Persister;
+use crate::io_worker::{IoWorker, MAX_SLOTS, Slot};
+use crate::log::{self, Target};
+use crate::metrics::{self, Metrics, WorkerMetrics};
+use crate::notify;
 use crate::sqes::{
     send_add_dev_cmd, send_del_dev_cmd, send_get_info_cmd,
-    send_set_params_cmd, send_start_recover_dev_cmd,
+    send_get_params_cmd, send_get_queue_affinity_cmd,
+    send_quiesce_dev_cmd, send_set_params_cmd, send_start_recover_dev_cmd,
     send_start_recovery_cmd, send_stop_dev_cmd,
 };
+use crate::trace::Trace;
 use crate::types::{AddResult, Ring128};
 
 use crate::cli::Start;
-use crate::util::set_fsids;
-
-const UBLK_CONTROL_FD_IDX: Fixed = Fixed(0);
-
-fn check_admin() -> Result<()> {
-    let is_admin =
-        !caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN)
-            .unwrap_or(false);
-
-    if is_admin {
-        bail!(
-            "Running without CAP_SYS_ADMIN (~as a non-root user) is not \
-            supported yet."
-        );
-    }
+use crate::ctrl::{UBLK_CONTROL_FD_IDX, create_ctrl_ring, open_ublk_ctrl};
+use crate::util::{reset_fsids, set_fsids, sync_chunks, ublkc_path};
 
-    Ok(())
+fn is_privileged() -> bool {
+    caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN)
+        .unwrap_or(false)
 }
 
-fn set_io_flusher() {
+fn set_io_flusher(privileged: bool) {
     const PR_SET_IO_FLUSHER: libc::c_int = 57;
 
+    // Requires CAP_SYS_RESOURCE which an unprivileged user is not going to
+    // have.
+    if !privileged {
+        debug!("Skipping PR_SET_IO_FLUSHER for an unprivileged device.");
+        return;
+    }
+
     if unsafe { libc::prctl(PR_SET_IO_FLUSHER, 1, 0, 0, 0) } < 0 {
         let err: anyhow::Error = io::Error::last_os_error().into();
 
@@ -66,13 +82,66 @@ fn set_io_flusher() {
     }
 }
 
-fn set_rlimit_nofile() {
-    const OUGHT_TO_BE_ENOUGH: u64 = 400_000;
+/// Polling only works with direct IO and a device which has poll queues.
+fn check_iopoll(config: &mut Config) {
+    if !config.iopoll() {
+        return;
+    }
+
+    if !config.direct_io() {
+        warn!("iopoll requires direct-io to be on. Not polling.");
+        config.iopoll = Some(false);
+        return;
+    }
+
+    if !config.io_poll_supported().unwrap_or_default() {
+        warn!(
+            "The device backing the repository doesn't support polling \
+            (see /sys/block/<dev>/queue/io_poll). Not polling."
+        );
+        config.iopoll = Some(false);
+        return;
+    }
+
+    // Timeouts cannot be linked to entries of a poll ring.
+    if config.io_timeout().is_some() {
+        warn!(
+            "io-timeout doesn't apply to reads and writes while iopoll is \
+            on."
+        );
+    }
+}
+
+/// Every worker has up to max-open-chunks chunks open. The chunks are
+/// shared but in the worst case, each worker has a different set.
+fn set_rlimit_nofile(config: &Config, privileged: bool) {
+    let nr_workers = if config.per_io_daemon() {
+        config.io_threads()
+    } else {
+        config.threads()
+    }
+    .unwrap_or(1) as u64;
+    let wanted = config.max_open_chunks() as u64 * nr_workers + 1024;
 
     match resource::getrlimit(Resource::RLIMIT_NOFILE) {
         Ok((soft_limit, hard_limit)) => {
-            let soft_limit = soft_limit.max(OUGHT_TO_BE_ENOUGH);
-            let hard_limit = hard_limit.max(OUGHT_TO_BE_ENOUGH);
+            // Only a privileged process can raise the hard limit. Otherwise
+            // go as high as the hard limit allows.
+            let (soft_limit, hard_limit) = if privileged {
+                (soft_limit.max(wanted), hard_limit.max(wanted))
+            } else {
+                (soft_limit.max(wanted.min(hard_limit)), hard_limit)
+            };
+
+            if soft_limit < wanted {
+                warn!(
+                    "The current limit on open file descriptors is {} and \
+                    cannot be raised without CAP_SYS_RESOURCE. Lower \
+                    max-open-chunks to {} or less.",
+                    hard_limit,
+                    hard_limit.saturating_sub(1024) / nr_workers
+                );
+            }
 
             if let Err(err) = resource::setrlimit(
                 Resource::RLIMIT_NOFILE,
@@ -80,10 +149,10 @@ fn set_rlimit_nofile() {
                 hard_limit,
             ) {
                 warn!(
-                    "Unable to change the current limit on open file descriptors to {}. \
-                    Depending on chunk size, blkchnkr might need to open a lot files \
-                    per thread and this might fail if the limit is too low. Err: {}",
-                    OUGHT_TO_BE_ENOUGH, err
+                    "Unable to change the current limit on open file \
+                    descriptors to {}. Workers might fail to start if the \
+                    limit is lower than max-open-chunks. Err: {}",
+                    wanted, err
                 );
             }
         }
@@ -98,23 +167,11 @@ fn set_rlimit_nofile() {
     };
 }
 
-#[inline(always)]
-fn open_ublk_ctrl() -> Result<OwnedFd> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("/dev/ublk-control")
-        .context(
-            "Unable to open /dev/ublk-control. Make sure the kernel \
-            module ublk_drv is loaded and accessible to the current user.",
-        )?;
-
-    Ok(file.into())
-}
-
-fn open_ublkc_dev(dev_id: u32) -> Result<OwnedFd> {
-    // It might take a while before the device shows up.
-    let path = format!("/dev/ublkc{}", dev_id);
+fn open_ublkc_dev(dev_info: &ublksrv_ctrl_dev_info) -> Result<OwnedFd> {
+    // It might take a while before the device shows up. The permissions
+    // of unprivileged devices are adjusted by udev only after that.
+    let path = ublkc_path(dev_info.dev_id);
+    let mut last_err = None;
 
     for _ in 0..3 {
         let file = OpenOptions::new().read(true).write(true).open(&path);
@@ -125,6 +182,11 @@ fn open_ublkc_dev(dev_id: u32) -> Result<OwnedFd> {
                 io::ErrorKind::NotFound
                 | io::ErrorKind::ResourceBusy
                 | io::ErrorKind::Interrupted => {}
+                io::ErrorKind::PermissionDenied
+                    if dev_info.is_unprivileged() =>
+                {
+                    last_err = Some(err);
+                }
                 _ => {
                     bail!("Unable to open {}, err: {}", path, err);
                 }
@@ -134,39 +196,63 @@ fn open_ublkc_dev(dev_id: u32) -> Result<OwnedFd> {
         sleep(Duration::from_millis(150));
     }
 
-    bail!("Unable to open {}", path)
-}
-
-fn create_ctrl_ring(fd: OwnedFd) -> Result<Ring128> {
-    let ring = Ring128::builder()
-        .setup_coop_taskrun()
-        .setup_single_issuer()
-        .build(8)?;
-
-    // UBLK_CONTROL_FD_IDX
-    ring.submitter().register_files(&[fd.as_raw_fd()])?;
+    if let Some(err) = last_err {
+        bail!(
+            "Unable to open {}, err: {}. Unprivileged devices require a \
+            udev rule which hands /dev/ublkcN and /dev/ublkbN over to the \
+            device's owner.",
+            path,
+            err
+        );
+    }
 
-    Ok(ring)
+    bail!("Unable to open {}", path)
 }
 
 fn add_new_dev(
     config: &Config,
     ring: &mut Ring128,
+    privileged: bool,
 ) -> Result<(bool, ublksrv_ctrl_dev_info)> {
+    // Quiescing relies on user recovery to resume the device.
+    let mut flags = UBLK_F_USER_RECOVERY | UBLK_F_QUIESCE;
+
+    if !privileged {
+        flags |= UBLK_F_UNPRIVILEGED_DEV;
+    }
+
+    if config.per_io_daemon() {
+        flags |= UBLK_F_PER_IO_DAEMON;
+    }
+
     let dev_info = ublksrv_ctrl_dev_info {
         dev_id: config.dev_id(),
         nr_hw_queues: config.threads()?,
         max_io_buf_bytes: 512 << 11,
         queue_depth: 128,
-        flags: UBLK_F_USER_RECOVERY.into(),
+        flags: flags.into(),
         ..Default::default()
     };
 
     match send_add_dev_cmd(dev_info, ring, UBLK_CONTROL_FD_IDX) {
         Ok(result) => match result {
-            AddResult::NewDevice(dev_info) => Ok((true, dev_info)),
+            AddResult::NewDevice(dev_info) => {
+                // The driver silently drops flags it doesn't know about.
+                if config.per_io_daemon() && !dev_info.is_per_io_daemon() {
+                    warn!(
+                        "The kernel doesn't support UBLK_F_PER_IO_DAEMON. \
+                        Falling back to one thread per queue."
+                    );
+                }
+
+                Ok((true, dev_info))
+            }
             AddResult::AttemptRecovery => attempt_recovery(config, ring),
         },
+        Err(err) if !privileged => Err(err.context(
+            "Unable to create an unprivileged device. Make sure the \
+            kernel supports UBLK_F_UNPRIVILEGED_DEV (Linux 6.5+).",
+        )),
         Err(err) => Err(err),
     }
 }
@@ -210,6 +296,10 @@ fn dev_attrs(config: &mut Config) -> Result<u32> {
         attrs |= UBLK_ATTR_FUA;
     }
 
+    if config.rotational()? {
+        attrs |= UBLK_ATTR_ROTATIONAL;
+    }
+
     Ok(attrs)
 }
 
@@ -220,7 +310,9 @@ fn set_dev_params(
 ) -> Result<()> {
     let params = ublk_params {
         len: ublk_params::len() as _,
-        types: UBLK_PARAM_TYPE_BASIC | UBLK_PARAM_TYPE_DMA_ALIGN,
+        types: UBLK_PARAM_TYPE_BASIC
+            | UBLK_PARAM_TYPE_DMA_ALIGN
+            | UBLK_PARAM_TYPE_SEGMENT,
         basic: ublk_param_basic {
             attrs: dev_attrs(config)?,
             logical_bs_shift: config.logical_bs_shift()?,
@@ -235,80 +327,445 @@ fn set_dev_params(
             alignment: config.dma_alignment()?,
             ..Default::default()
         },
+        seg: ublk_param_segment {
+            // The buffers are contiguous, there are no boundaries to
+            // respect.
+            seg_boundary_mask: u32::MAX as u64,
+            max_segment_size: config.max_segment_size()?,
+            max_segments: config.max_segments()?,
+            ..Default::default()
+        },
         ..Default::default()
     };
 
     send_set_params_cmd(dev_info, params, ring, UBLK_CONTROL_FD_IDX)
 }
 
-fn setup_signals() -> Result<OwnedFd> {
+fn setup_signals() -> Result<SignalFd> {
     let mut set = SigSet::empty();
     set.add(Signal::SIGINT);
     set.add(Signal::SIGTERM);
+    set.add(Signal::SIGUSR1);
+    set.add(Signal::SIGUSR2);
 
     signal::sigprocmask(SigmaskHow::SIG_BLOCK, Some(&set), None)?;
 
-    Ok(SignalFd::with_flags(&set, SfdFlags::SFD_NONBLOCK)?.into())
+    Ok(SignalFd::with_flags(&set, SfdFlags::SFD_NONBLOCK)?)
 }
 
-fn handle_sigint(ring: &mut Ring128, fd: OwnedFd) -> Result<()> {
-    let sqe = PollAdd::new(Fd(fd.as_raw_fd()), libc::POLLIN as _).build();
-    let sqe = sqe.user_data(42);
+const SIGNAL_POLL: u64 = 42;
+const CONTROL_POLL: u64 = 43;
+const CANCEL_POLL: u64 = 44;
+const TIMEOUT_POLL: u64 = 45;
+
+/// How long the workers have to carry out a command.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum Event {
+    Signal(Signal),
+    Control(UnixStream),
+    Timeout,
+}
+
+/// Waits for a signal or a connection to the control socket, or until the
+/// timeout expires.
+fn wait_for_event(
+    ring: &mut Ring128,
+    signal_fd: &SignalFd,
+    control: Option<&ControlSocket>,
+    timeout: Option<Duration>,
+) -> Result<Event> {
+    let mut fds = vec![(SIGNAL_POLL, signal_fd.as_raw_fd())];
+    if let Some(control) = control {
+        fds.push((CONTROL_POLL, control.as_raw_fd()));
+    }
+
+    let timeout = timeout.map(Timespec::from);
 
-    unsafe { ring.submission().push(&sqe.into())? };
-    ring.submit_and_wait(1)?;
+    loop {
+        for &(user_data, fd) in &fds {
+            let sqe = PollAdd::new(Fd(fd), libc::POLLIN as _).build();
+            let sqe = sqe.user_data(user_data);
 
-    if let Some(cqe) = ring.completion().next() {
-        if cqe.user_data() != 42 {
-            bail!("Unexpected message.");
+            unsafe { ring.submission().push(&sqe.into())? };
         }
 
-        if cqe.result() < 0 {
-            error!(
-                "Polling failed with err {}. Shutting down anyway.",
-                cqe.result()
-            );
+        let mut polls: Vec<u64> = fds.iter().map(|(ud, _)| *ud).collect();
+
+        if let Some(timeout) = &timeout {
+            let sqe =
+                Timeout::new(timeout).build().user_data(TIMEOUT_POLL);
+
+            unsafe { ring.submission().push(&sqe.into())? };
+            polls.push(TIMEOUT_POLL);
+        }
+
+        let mut inflight = polls.len();
+        let mut failed = false;
+        let mut canceled = false;
+        let mut timed_out = false;
+
+        // The ring is shared with the driver commands, which expect
+        // nothing else to be in flight. Once one of the polls completes,
+        // the others are canceled.
+        while inflight > 0 {
+            ring.submit_and_wait(1)?;
+
+            for cqe in ring.completion() {
+                inflight -= 1;
+
+                match cqe.user_data() {
+                    SIGNAL_POLL | CONTROL_POLL => {
+                        polls.retain(|ud| *ud != cqe.user_data());
+
+                        if cqe.result() < 0
+                            && cqe.result() != -libc::ECANCELED
+                        {
+                            error!(
+                                "Polling failed with err {}. Shutting \
+                                down anyway.",
+                                cqe.result()
+                            );
+                            failed = true;
+                        }
+                    }
+                    TIMEOUT_POLL => {
+                        polls.retain(|ud| *ud != TIMEOUT_POLL);
+                        timed_out = cqe.result() == -libc::ETIME;
+                    }
+                    CANCEL_POLL => {}
+                    _ => bail!("Unexpected message."),
+                }
+            }
+
+            if inflight > 0 && !canceled {
+                for &user_data in &polls {
+                    let sqe = AsyncCancel::new(user_data).build();
+                    let sqe = sqe.user_data(CANCEL_POLL);
+
+                    unsafe { ring.submission().push(&sqe.into())? };
+                    inflight += 1;
+                }
+                canceled = true;
+            }
+        }
+
+        if failed {
+            return Ok(Event::Signal(Signal::SIGTERM));
+        }
+
+        if let Some(info) = signal_fd.read_signal()? {
+            return Ok(Event::Signal(Signal::try_from(
+                info.ssi_signo as i32,
+            )?));
         }
+
+        if let Some(control) = control
+            && let Some(conn) = control.accept()?
+        {
+            return Ok(Event::Control(conn));
+        }
+
+        if timed_out {
+            return Ok(Event::Timeout);
+        }
+    }
+}
+
+/// The worker threads serving a live device and the char device they
+/// share.
+struct Workers {
+    threads: Box<[JoinHandle<()>]>,
+    controls: Box<[WorkerControl]>,
+    registry: Arc<ChunkRegistry>,
+    ublkc_dev_fd: OwnedFd,
+}
+
+fn start_workers(
+    config: &Config,
+    dev_info: &ublksrv_ctrl_dev_info,
+    ublk_ctrl_fd: &OwnedFd,
+    metrics: &Metrics,
+    trace: Option<&Arc<Trace>>,
+) -> Result<Workers> {
+    // The char device is opened with the process' ids while the workers
+    // must create chunks with the configured ones.
+    reset_fsids();
+    let ublkc_dev_fd = open_ublkc_dev(dev_info)?;
+    set_fsids(config);
+
+    let registry = Arc::new(ChunkRegistry::new(config)?);
+    let (threads, controls): (Vec<_>, Vec<_>) = start_worker_threads(
+        config,
+        dev_info,
+        ublk_ctrl_fd,
+        &ublkc_dev_fd,
+        &registry,
+        metrics,
+        trace,
+    )?
+    .into_iter()
+    .unzip();
+
+    Ok(Workers {
+        threads: threads.into_boxed_slice(),
+        controls: controls.into_boxed_slice(),
+        registry,
+        ublkc_dev_fd,
+    })
+}
+
+/// Drains in-flight IO and stops the workers, which sync all the chunks
+/// they have open. The driver holds new IO until the device is resumed.
+fn quiesce(
+    config: &Config,
+    dev_info: &ublksrv_ctrl_dev_info,
+    ring: &mut Ring128,
+    workers: &mut Option<Workers>,
+) -> Result<()> {
+    if workers.is_none() {
+        bail!("The device is already quiesced.");
+    }
+
+    if !dev_info.supports_quiesce() {
+        bail!("The kernel doesn't support quiescing (UBLK_F_QUIESCE).");
+    }
+
+    info!("Quiescing...");
+    send_quiesce_dev_cmd(dev_info, 3000, ring, UBLK_CONTROL_FD_IDX)?;
+
+    if let Some(workers) = workers.take() {
+        debug!("Waiting for all threads to finish...");
+        join_worker_threads(workers.threads, None)?;
+
+        // The char device must be closed for the recovery to start.
+        drop(workers.ublkc_dev_fd);
+    }
+
+    sync_chunks(config)?;
+    File::create(config.quiesced_path())
+        .context("Failed to create the quiesced marker.")?;
+
+    info!("Quiesced, synced the chunks");
+    notify::status("Quiesced");
+
+    Ok(())
+}
+
+/// Recovers the quiesced device with a fresh set of workers.
+fn resume(
+    config: &Config,
+    dev_info: &ublksrv_ctrl_dev_info,
+    ring: &mut Ring128,
+    ublk_ctrl_fd: &OwnedFd,
+    workers: &mut Option<Workers>,
+    metrics: &Metrics,
+    trace: Option<&Arc<Trace>>,
+) -> Result<()> {
+    if workers.is_some() {
+        bail!("The device is not quiesced.");
     }
 
+    info!("Resuming...");
+    send_start_recovery_cmd(*dev_info, ring, UBLK_CONTROL_FD_IDX)?;
+
+    *workers = Some(start_workers(
+        config,
+        dev_info,
+        ublk_ctrl_fd,
+        metrics,
+        trace,
+    )?);
+
+    send_start_recover_dev_cmd(
+        false,
+        dev_info,
+        ring,
+        process::id(),
+        UBLK_CONTROL_FD_IDX,
+    )?;
+
+    remove_quiesced_marker(config);
+
+    info!("Resumed");
+    notify::status(&format!("Serving /dev/ublkb{}", dev_info.dev_id));
+
     Ok(())
 }
 
+fn remove_quiesced_marker(config: &Config) {
+    if let Err(err) = fs::remove_file(config.quiesced_path())
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove the quiesced marker. Err: {}", err);
+    }
+}
+
+/// Distributes the tags of all queues across worker threads. Normally,
+/// each worker serves all tags of exactly one queue. With
+/// UBLK_F_PER_IO_DAEMON, the tags of every queue are interleaved across
+/// the whole pool such that a single busy queue is served by all threads.
+fn assign_slots(
+    config: &Config,
+    dev_info: &ublksrv_ctrl_dev_info,
+) -> Result<Vec<Vec<Slot>>> {
+    let nr_queues = dev_info.nr_hw_queues;
+    let depth = dev_info.queue_depth;
+
+    if !dev_info.is_per_io_daemon() {
+        return Ok((0..nr_queues)
+            .map(|queue_id| {
+                (0..depth).map(|tag| Slot { queue_id, tag }).collect()
+            })
+            .collect());
+    }
+
+    let nr_threads = config.io_threads()?.max(1) as usize;
+    let per_thread =
+        (nr_queues as usize * depth as usize).div_ceil(nr_threads);
+
+    if per_thread > MAX_SLOTS {
+        bail!(
+            "Every IO thread would serve {} requests ({} queues of depth {} \
+            over {} threads) but at most {} are supported. Raise \
+            io-threads.",
+            per_thread,
+            nr_queues,
+            depth,
+            nr_threads,
+            MAX_SLOTS
+        );
+    }
+
+    let mut assignments = vec![Vec::new(); nr_threads];
+
+    for queue_id in 0..nr_queues {
+        for tag in 0..depth {
+            let thread = (queue_id as usize + tag as usize) % nr_threads;
+            assignments[thread].push(Slot { queue_id, tag });
+        }
+    }
+
+    Ok(assignments
+        .into_iter()
+        .filter(|slots| !slots.is_empty())
+        .collect())
+}
+
 fn start_worker_threads(
     config: &Config,
     dev_info: &ublksrv_ctrl_dev_info,
+    ublk_ctrl_fd: &OwnedFd,
     ublkc_dev_fd: &OwnedFd,
-) -> Result<Box<[JoinHandle<()>]>> {
-    let mut queue_threads =
-        Vec::with_capacity(dev_info.nr_hw_queues.into());
+    registry: &Arc<ChunkRegistry>,
+    metrics: &Metrics,
+    trace: Option<&Arc<Trace>>,
+) -> Result<Vec<(JoinHandle<()>, WorkerControl)>> {
+    let assignments = assign_slots(config, dev_info)?;
+    let mut worker_threads = Vec::with_capacity(assignments.len());
 
     let dev_info = *dev_info;
+    let ublk_ctrl_fd = ublk_ctrl_fd.as_raw_fd();
     let ublkc_dev_fd = ublkc_dev_fd.as_raw_fd();
 
-    for i in 0..dev_info.nr_hw_queues as usize {
+    for (i, slots) in assignments.into_iter().enumerate() {
         let config = config.clone();
+        let registry = registry.clone();
+        let metrics = metrics.register_worker();
+        let (control, inbox) = worker_channel()?;
+        let trace = trace.cloned();
+
+        let thread = thread::Builder::new()
+            .name(format!("tid={} worker", i))
+            .spawn(move || {
+                worker_thread_fn(
+                    i,
+                    slots.into_boxed_slice(),
+                    config,
+                    dev_info,
+                    ublk_ctrl_fd,
+                    ublkc_dev_fd,
+                    registry,
+                    metrics,
+                    inbox,
+                    trace,
+                );
+            })?;
 
-        queue_threads.push(
-            thread::Builder::new()
-                .name(format!("tid={} worker", i))
-                .spawn(move || {
-                    worker_thread_fn(i, config, dev_info, ublkc_dev_fd);
-                })?,
-        );
+        worker_threads.push((thread, control));
     }
 
-    Ok(queue_threads.into_boxed_slice())
+    Ok(worker_threads)
+}
+
+/// Pins the current thread to the CPUs blk-mq maps the served queues to
+/// such that requests are handled on the same CPU they were submitted from.
+fn pin_worker_thread(
+    slots: &[Slot],
+    config: &Config,
+    dev_info: &ublksrv_ctrl_dev_info,
+    ublk_ctrl_fd: RawFd,
+) -> Result<()> {
+    let mut ring = create_ctrl_ring(ublk_ctrl_fd)?;
+    let mut mask = vec![0u8; size_of::<CpuSet>()];
+
+    let mut queue_ids: Vec<u16> =
+        slots.iter().map(|slot| slot.queue_id).collect();
+    queue_ids.dedup();
+
+    for queue_id in queue_ids {
+        let queue_mask = send_get_queue_affinity_cmd(
+            dev_info,
+            queue_id,
+            mask.len(),
+            &mut ring,
+            UBLK_CONTROL_FD_IDX,
+        )?;
+
+        mask.iter_mut().zip(queue_mask).for_each(|(a, b)| *a |= b);
+    }
+
+    let Some(cpu_set) = cpu_set_for_queue(&mask, config.cpus()) else {
+        bail!("The queues are not mapped to any CPU.");
+    };
+
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)?;
+
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn worker_thread_fn(
-    queue_id: usize,
+    worker_id: usize,
+    slots: Box<[Slot]>,
     config: Config,
     dev_info: ublksrv_ctrl_dev_info,
+    ublk_ctrl_fd: RawFd,
     ublkc_dev_fd: RawFd,
+    registry: Arc<ChunkRegistry>,
+    metrics: Arc<WorkerMetrics>,
+    inbox: WorkerInbox,
+    trace: Option<Arc<Trace>>,
 ) {
     debug!("online");
 
-    match IoWorker::new(queue_id, config, dev_info, ublkc_dev_fd) {
+    if let Err(err) =
+        pin_worker_thread(&slots, &config, &dev_info, ublk_ctrl_fd)
+    {
+        warn!("Unable to set the CPU affinity of a worker. Err: {}", err);
+    }
+
+    match IoWorker::new(
+        worker_id,
+        slots,
+        config,
+        dev_info,
+        ublkc_dev_fd,
+        registry,
+        metrics,
+        inbox,
+        trace,
+    ) {
         Ok(mut worker) => {
             if let Err(err) = worker.work() {
                 error!("Worker crashed. Err: {err}");
@@ -318,25 +775,208 @@ fn worker_thread_fn(
     }
 }
 
-#[inline(always)]
-fn join_worker_threads(worker_threads: Box<[JoinHandle<()>]>) {
+/// Sends the command to all workers and sums up the number of chunks they
+/// report.
+fn command_workers(
+    workers: Option<&Workers>,
+    command: WorkerCommand,
+) -> Result<usize> {
+    let Some(workers) = workers else {
+        bail!("The device is quiesced.");
+    };
+
+    let receivers = workers
+        .controls
+        .iter()
+        .map(|control| control.send(command))
+        .collect::<Result<Vec<_>>>()?;
+
+    let deadline = Instant::now() + WORKER_TIMEOUT;
+    let watchdog = notify::watchdog_interval();
+    let mut total = 0;
+
+    for receiver in receivers {
+        // A slow flush may take longer than the service manager waits for
+        // the watchdog, it's kept pinged meanwhile.
+        total += loop {
+            let remaining =
+                deadline.saturating_duration_since(Instant::now());
+            let timeout = watchdog
+                .map_or(remaining, |watchdog| watchdog.min(remaining));
+
+            match receiver.recv_timeout(timeout) {
+                Ok(affected) => break affected,
+                Err(RecvTimeoutError::Timeout) if !remaining.is_zero() => {
+                    notify::watchdog();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("A worker didn't respond in time.")
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    bail!("A worker has exited.")
+                }
+            }
+        };
+    }
+
+    Ok(total)
+}
+
+fn status(
+    dev_info: &ublksrv_ctrl_dev_info,
+    workers: Option<&Workers>,
+    started: Instant,
+) -> String {
+    let state = if workers.is_some() {
+        "live"
+    } else {
+        "quiesced"
+    };
+
+    format!(
+        "device: /dev/ublkb{}\n\
+        pid: {}\n\
+        state: {}\n\
+        workers: {}\n\
+        open chunks: {}\n\
+        log level: {}\n\
+        uptime: {}s\n",
+        dev_info.dev_id,
+        process::id(),
+        state,
+        workers.map_or(0, |workers| workers.threads.len()),
+        workers.map_or(0, |workers| workers.registry.nr_open()),
+        log::level(),
+        started.elapsed().as_secs()
+    )
+}
+
+/// Carries out a request sent to the control socket. Requests which
+/// change the state of the device are handled by the caller.
+fn handle_request(
+    request: Request,
+    dev_info: &ublksrv_ctrl_dev_info,
+    workers: Option<&Workers>,
+    metrics: &Metrics,
+    started: Instant,
+) -> Result<String> {
+    match request {
+        Request::Status => Ok(status(dev_info, workers, started)),
+        Request::Stats => Ok(metrics.render_stats()),
+        Request::SetLogLevel(level) => {
+            log::set_level(level);
+            info!("Log level set to {}", level);
+            Ok(String::new())
+        }
+        Request::Flush => {
+            let synced = command_workers(workers, WorkerCommand::Flush)?;
+            Ok(format!("Synced {} chunks\n", synced))
+        }
+        Request::CloseIdleChunks => {
+            let closed =
+                command_workers(workers, WorkerCommand::CloseIdleChunks)?;
+            Ok(format!("Closed {} chunks\n", closed))
+        }
+        Request::Quiesce | Request::Resume | Request::Stop => {
+            unreachable!("handled by the caller")
+        }
+    }
+}
+
+/// Fails if the workers aren't done within the timeout.
+fn join_worker_threads(
+    worker_threads: Box<[JoinHandle<()>]>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    if let Some(timeout) = timeout {
+        let deadline = Instant::now() + timeout;
+
+        while worker_threads.iter().any(|t| !t.is_finished()) {
+            if Instant::now() >= deadline {
+                let busy =
+                    worker_threads.iter().filter(|t| !t.is_finished());
+
+                bail!(
+                    "{} workers didn't finish within {}s.",
+                    busy.count(),
+                    timeout.as_secs()
+                );
+            }
+
+            sleep(Duration::from_millis(10));
+        }
+    }
+
     worker_threads.into_iter().for_each(|t| _ = t.join());
+
+    Ok(())
 }
 
 pub fn run(start: Start) -> Result<()> {
-    info!("Starting up (v{})", env!("CARGO_PKG_VERSION"));
+    if let Some(level) = start.log_level {
+        log::set_level(level);
+    }
+    if let Some(format) = start.log_format {
+        log::set_format(format);
+    }
+    if let Some(target) = start.log_target {
+        log::set_target(target)?;
+    }
+
+    // Nobody would see the messages otherwise.
+    if start.daemon
+        && start.log_file.is_none()
+        && matches!(log::target(), Target::Stdout | Target::Stderr)
+    {
+        log::set_target(Target::Syslog)?;
+    }
+
+    let config = Config::from_repository(start.repository.clone())?;
+
+    // Held until the server exits, also across the fork into the
+    // background.
+    let _lock = config.lock()?;
 
-    check_admin()?;
+    let mut daemon = if start.daemon {
+        Some(daemon::daemonize(&config, start.log_file.as_deref())?)
+    } else {
+        None
+    };
 
-    let mut config = Config::from_repository(start.repository)?;
+    let result = serve(start, config, daemon.as_mut());
 
-    set_io_flusher();
-    set_rlimit_nofile();
+    if let (Err(err), Some(daemon)) = (&result, &mut daemon) {
+        daemon.failed(err);
+    }
 
-    let fd = open_ublk_ctrl()?;
-    let mut ring = create_ctrl_ring(fd)?;
+    result
+}
 
-    let (is_new_device, dev_info) = add_new_dev(&config, &mut ring)?;
+fn serve(
+    start: Start,
+    mut config: Config,
+    mut daemon: Option<&mut Daemon>,
+) -> Result<()> {
+    info!("Starting up (v{})", env!("CARGO_PKG_VERSION"));
+    let started = Instant::now();
+
+    let privileged = is_privileged();
+    if !privileged {
+        info!(
+            "Running without CAP_SYS_ADMIN, the device will be created as \
+            unprivileged."
+        );
+    }
+
+    set_io_flusher(privileged);
+    set_rlimit_nofile(&config, privileged);
+    check_iopoll(&mut config);
+
+    let ublk_ctrl_fd = open_ublk_ctrl()?;
+    let mut ring = create_ctrl_ring(ublk_ctrl_fd.as_raw_fd())?;
+
+    let (is_new_device, dev_info) =
+        add_new_dev(&config, &mut ring, privileged)?;
 
     if is_new_device {
         set_dev_params(&mut config, &dev_info, &mut ring)?;
@@ -344,17 +984,43 @@ pub fn run(start: Start) -> Result<()> {
 
     debug!("dev_info={:#?}", dev_info);
 
-    // Set the filesystem ids only after we've opened and setup the
-    // devices.
-    set_fsids(&config);
-
     // Close the fd gracefully on exit. Set up the signals here such that
     // the block is inherited by worker threads.
     let signal_fd = setup_signals()?;
-    let ublkc_dev_fd = open_ublkc_dev(dev_info.dev_id)?;
 
-    let worker_threads =
-        start_worker_threads(&config, &dev_info, &ublkc_dev_fd)?;
+    let metrics = Arc::new(Metrics::new(&config, dev_info.nr_hw_queues));
+    if let Err(err) = metrics::serve(&config, metrics.clone()) {
+        error!("Failed to serve metrics. Err: {}", err);
+    }
+
+    let heatmap = Persister::start(&config, metrics.clone())
+        .inspect_err(|err| {
+            error!("Failed to track chunk accesses. Err: {}", err)
+        })
+        .ok();
+
+    let control = ControlSocket::bind(&config)
+        .inspect_err(|err| {
+            error!("Failed to open the control socket. Err: {}", err)
+        })
+        .ok();
+
+    let (trace, trace_writer) = match &start.trace {
+        Some(path) => {
+            let (trace, writer) = Trace::create(path, start.trace_size)?;
+            info!("Tracing requests to {}", path.display());
+            (Some(trace), Some(writer))
+        }
+        None => (None, None),
+    };
+
+    let mut workers = Some(start_workers(
+        &config,
+        &dev_info,
+        &ublk_ctrl_fd,
+        &metrics,
+        trace.as_ref(),
+    )?);
 
     send_start_recover_dev_cmd(
         is_new_device,
@@ -363,32 +1029,162 @@ pub fn run(start: Start) -> Result<()> {
         process::id(),
         UBLK_CONTROL_FD_IDX,
     )?;
+    remove_quiesced_marker(&config);
+
+    let devt =
+        send_get_params_cmd(&dev_info, &mut ring, UBLK_CONTROL_FD_IDX)
+            .map(|params| {
+                format!(
+                    " ({}:{})",
+                    params.devt.disk_major, params.devt.disk_minor
+                )
+            })
+            .unwrap_or_default();
 
     if is_new_device {
         info!(
-            "Created a new block device at /dev/ublkb{}",
-            dev_info.dev_id
+            "Created a new block device at /dev/ublkb{}{}",
+            dev_info.dev_id, devt
         );
     } else {
         info!(
-            "Recovered the block device at /dev/ublkb{}",
-            dev_info.dev_id
+            "Recovered the block device at /dev/ublkb{}{}",
+            dev_info.dev_id, devt
         );
     }
     info!("Ready!");
 
-    handle_sigint(&mut ring, signal_fd)?;
+    let serving = format!("Serving /dev/ublkb{}", dev_info.dev_id);
+    notify::ready(&serving);
+    if let Some(daemon) = &mut daemon {
+        daemon.ready();
+    }
+
+    let watchdog = notify::watchdog_interval();
+
+    loop {
+        let event = wait_for_event(
+            &mut ring,
+            &signal_fd,
+            control.as_ref(),
+            watchdog,
+        )?;
+
+        // Pinged after every event, the loop only gets stuck if one of
+        // them does.
+        if watchdog.is_some() {
+            notify::watchdog();
+        }
+
+        match event {
+            Event::Timeout => {}
+            Event::Signal(Signal::SIGUSR1) => {
+                if let Err(err) =
+                    quiesce(&config, &dev_info, &mut ring, &mut workers)
+                {
+                    error!("Failed to quiesce the device. Err: {}", err);
+                }
+            }
+            Event::Signal(Signal::SIGUSR2) => {
+                if let Err(err) = resume(
+                    &config,
+                    &dev_info,
+                    &mut ring,
+                    &ublk_ctrl_fd,
+                    &mut workers,
+                    &metrics,
+                    trace.as_ref(),
+                ) {
+                    error!("Failed to resume the device. Err: {}", err);
+                }
+            }
+            Event::Signal(_) => break,
+            Event::Control(mut conn) => {
+                let result = match control::read_request(&mut conn) {
+                    Ok(Request::Quiesce) => quiesce(
+                        &config,
+                        &dev_info,
+                        &mut ring,
+                        &mut workers,
+                    )
+                    .map(|_| String::new()),
+                    Ok(Request::Resume) => resume(
+                        &config,
+                        &dev_info,
+                        &mut ring,
+                        &ublk_ctrl_fd,
+                        &mut workers,
+                        &metrics,
+                        trace.as_ref(),
+                    )
+                    .map(|_| String::new()),
+                    Ok(Request::Stop) => {
+                        control::respond(&mut conn, Ok(String::new()));
+                        break;
+                    }
+                    Ok(request) => handle_request(
+                        request,
+                        &dev_info,
+                        workers.as_ref(),
+                        &metrics,
+                        started,
+                    ),
+                    Err(err) => Err(err),
+                };
+
+                control::respond(&mut conn, result);
+            }
+        }
+    }
 
     info!("Stopping...");
+    notify::stopping();
     send_stop_dev_cmd(&dev_info, &mut ring, UBLK_CONTROL_FD_IDX)?;
 
-    debug!("Waiting for all threads to finish...");
-    join_worker_threads(worker_threads);
+    let mut drained = Ok(());
+    let mut synced = Ok(());
+
+    if let Some(workers) = workers {
+        debug!("Waiting for all threads to finish...");
+        let draining = Instant::now();
+
+        // If the requests don't drain, whatever has made it to the chunks
+        // is still synced and the rest of the teardown carried out. The
+        // hung workers go away with the process.
+        drained = join_worker_threads(
+            workers.threads,
+            config.shutdown_timeout(),
+        )
+        .context("Failed to drain the device.");
+        if let Err(err) = &drained {
+            error!("{:#}", err);
+        }
+
+        // The device is deleted even if the chunks couldn't be synced,
+        // the error is returned at the end.
+        synced = sync_chunks(&config);
+        match &synced {
+            Ok(()) => info!(
+                "Drained in {:.2}s, synced the chunks",
+                draining.elapsed().as_secs_f64()
+            ),
+            Err(err) => error!("{:#}", err),
+        }
+    }
+    remove_quiesced_marker(&config);
+
+    if let Some(Err(err)) = heatmap.map(|heatmap| heatmap.persist()) {
+        error!("Failed to persist the heatmap. Err: {}", err);
+    }
+
+    if let (Some(trace), Some(writer)) = (trace, trace_writer) {
+        writer.finish(trace);
+    }
 
     debug!("Deleting the device...");
     send_del_dev_cmd(&dev_info, &mut ring, UBLK_CONTROL_FD_IDX)?;
 
     info!("Bye");
 
-    Ok(())
+    drained.and(synced)
 }
@@ -0,0 +1,13 @@
+use anyhow::Result;
+
+use crate::cli::Stats;
+use crate::config::Config;
+use crate::control::send;
+
+pub fn run(stats: Stats) -> Result<()> {
+    let config = Config::from_repository(stats.repository)?;
+
+    print!("{}", send(&config, "stats")?);
+
+    Ok(())
+}
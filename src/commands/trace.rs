@@ -0,0 +1,10 @@
+use std::io;
+
+use anyhow::Result;
+
+use crate::cli::TraceDump;
+use crate::trace;
+
+pub fn dump(dump: TraceDump) -> Result<()> {
+    trace::dump(&dump.path, dump.csv, &mut io::stdout().lock())
+}
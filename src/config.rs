@@ -5,11 +5,22 @@ use std::{
     path::PathBuf,
     str::FromStr,
     thread,
+    time::Duration,
 };
 
 use anyhow::{Context, Ok, Result, anyhow, bail};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
 
+use crate::affinity::CpuList;
+use crate::allocation::Allocation;
+use crate::bindings::UBLK_MIN_SEGMENT_SIZE;
+use crate::metrics::MetricsListen;
 use crate::queue_limits::{QueueLimits, limits_from_device};
+use crate::retry::ErrnoList;
+
+/// Requests in flight keep their chunks open so leave some room.
+const MIN_OPEN_CHUNKS: u32 = 256;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -40,6 +51,79 @@ pub struct Config {
     /// Use direct IO.
     pub direct_io: Option<bool>,
 
+    /// Restrict the worker threads to these CPUs. Each worker is
+    /// otherwise pinned to the CPUs of its hardware queue.
+    pub cpus: Option<CpuList>,
+
+    /// Serve the tags of every queue by a pool of threads instead of one
+    /// thread per queue (UBLK_F_PER_IO_DAEMON).
+    pub per_io_daemon: Option<bool>,
+
+    /// The number of threads in the pool when per_io_daemon is enabled.
+    pub io_threads: Option<u16>,
+
+    /// Overrides whether the device is advertised as rotational.
+    pub rotational: Option<bool>,
+
+    /// Overrides the maximum number of segments of a request.
+    pub max_segments: Option<u16>,
+
+    /// Overrides the maximum size of a segment in bytes.
+    pub max_segment_size: Option<u32>,
+
+    /// Let a kernel thread poll the submission queue of every worker
+    /// (IORING_SETUP_SQPOLL).
+    pub sqpoll: Option<bool>,
+
+    /// How long the kernel thread polls before going to sleep, in ms.
+    pub sqpoll_idle: Option<u32>,
+
+    /// The CPUs the kernel threads are bound to, one per worker in turn.
+    pub sqpoll_cpus: Option<CpuList>,
+
+    /// Busy poll for completions of reads and writes
+    /// (IORING_SETUP_IOPOLL). Requires direct IO and a backing device
+    /// which supports polling.
+    pub iopoll: Option<bool>,
+
+    /// Fail operations on the backing store which take longer than this
+    /// many seconds. Disabled if 0 or not set.
+    pub io_timeout: Option<u32>,
+
+    /// Report requests which have been in flight for longer than this many
+    /// seconds. Disabled if 0.
+    pub hung_io_warning: Option<u32>,
+
+    /// How many seconds to wait for in-flight IO to drain and the chunks
+    /// to be synced on shutdown. Waits forever if 0.
+    pub shutdown_timeout: Option<u32>,
+
+    /// Failed operations on the backing store to retry.
+    pub retry_errnos: Option<ErrnoList>,
+
+    /// How many times to retry a failed operation.
+    pub retry_attempts: Option<u32>,
+
+    /// The initial wait before retrying an operation in ms.
+    pub retry_backoff: Option<u32>,
+
+    /// The number of chunks each worker keeps open.
+    pub max_open_chunks: Option<u32>,
+
+    /// How the space of chunks is allocated.
+    pub allocation: Option<Allocation>,
+
+    /// The number of chunks following a newly opened one to allocate with
+    /// the preallocate-ahead policy.
+    pub preallocate_ahead: Option<u32>,
+
+    /// Merge reads and writes next to each other in the same chunk into
+    /// vectored operations.
+    pub merge_io: Option<bool>,
+
+    /// Serve metrics on this Unix socket or localhost TCP port.
+    pub metrics: Option<MetricsListen>,
+
     /// The underlying device's queue limits. Loaded on demand.
     pub queue_limits: Option<QueueLimits>,
 }
@@ -66,6 +150,27 @@ impl Config {
             fsuid,
             fsgid,
             direct_io,
+            cpus: None,
+            per_io_daemon: None,
+            io_threads: None,
+            rotational: None,
+            max_segments: None,
+            max_segment_size: None,
+            sqpoll: None,
+            sqpoll_idle: None,
+            sqpoll_cpus: None,
+            iopoll: None,
+            io_timeout: None,
+            hung_io_warning: None,
+            shutdown_timeout: None,
+            retry_errnos: None,
+            retry_attempts: None,
+            retry_backoff: None,
+            max_open_chunks: None,
+            allocation: None,
+            preallocate_ahead: None,
+            merge_io: None,
+            metrics: None,
             queue_limits: None,
         }
     }
@@ -110,6 +215,100 @@ impl Config {
         self.direct_io.unwrap_or_default()
     }
 
+    pub fn per_io_daemon(&self) -> bool {
+        self.per_io_daemon.unwrap_or_default()
+    }
+
+    pub fn io_threads(&self) -> Result<u16> {
+        if let Some(io_threads) = self.io_threads {
+            Ok(io_threads)
+        } else {
+            self.threads()
+        }
+    }
+
+    pub fn cpus(&self) -> Option<&CpuList> {
+        self.cpus.as_ref()
+    }
+
+    pub fn sqpoll(&self) -> bool {
+        self.sqpoll.unwrap_or_default()
+    }
+
+    pub fn sqpoll_idle(&self) -> u32 {
+        self.sqpoll_idle.unwrap_or(1000)
+    }
+
+    /// The CPU the kernel thread polling the worker's submission queue is
+    /// bound to, if any.
+    pub fn sqpoll_cpu(&self, worker_id: usize) -> Option<u32> {
+        let cpus = self.sqpoll_cpus.as_ref()?;
+        let nr_cpus = cpus.iter().count();
+
+        cpus.iter().nth(worker_id % nr_cpus).map(|cpu| cpu as u32)
+    }
+
+    pub fn iopoll(&self) -> bool {
+        self.iopoll.unwrap_or_default()
+    }
+
+    pub fn io_timeout(&self) -> Option<Duration> {
+        match self.io_timeout {
+            None | Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs as _)),
+        }
+    }
+
+    pub fn hung_io_warning(&self) -> Option<Duration> {
+        match self.hung_io_warning.unwrap_or(30) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as _)),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        match self.shutdown_timeout.unwrap_or(60) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as _)),
+        }
+    }
+
+    pub fn retry_errnos(&self) -> Option<&ErrnoList> {
+        self.retry_errnos.as_ref()
+    }
+
+    pub fn retry_attempts(&self) -> u32 {
+        self.retry_attempts.unwrap_or(3)
+    }
+
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff.unwrap_or(10) as _)
+    }
+
+    pub fn max_open_chunks(&self) -> u32 {
+        self.max_open_chunks.unwrap_or(4096)
+    }
+
+    pub fn allocation(&self) -> Allocation {
+        self.allocation.unwrap_or(Allocation::Sparse)
+    }
+
+    pub fn preallocate_ahead(&self) -> u32 {
+        self.preallocate_ahead.unwrap_or(4)
+    }
+
+    pub fn merge_io(&self) -> bool {
+        self.merge_io.unwrap_or_default()
+    }
+
+    pub fn metrics(&self) -> Option<&MetricsListen> {
+        self.metrics.as_ref()
+    }
+
+    pub fn io_poll_supported(&mut self) -> Result<bool> {
+        Ok(self.queue_limits()?.io_poll)
+    }
+
     pub fn logical_bs_shift(&mut self) -> Result<u8> {
         Ok(self.queue_limits()?.logical_block_size.ilog2() as _)
     }
@@ -139,6 +338,32 @@ impl Config {
         Ok(self.queue_limits()?.fua)
     }
 
+    pub fn rotational(&mut self) -> Result<bool> {
+        match self.rotational {
+            Some(rotational) => Ok(rotational),
+            None => Ok(self.queue_limits()?.rotational),
+        }
+    }
+
+    pub fn max_segments(&mut self) -> Result<u16> {
+        let max_segments = match self.max_segments {
+            Some(max_segments) => max_segments,
+            None => self.queue_limits()?.max_segments,
+        };
+
+        Ok(max_segments.max(1))
+    }
+
+    pub fn max_segment_size(&mut self) -> Result<u32> {
+        let size = match self.max_segment_size {
+            Some(max_segment_size) => max_segment_size,
+            None => self.queue_limits()?.max_segment_size,
+        };
+
+        // The driver refuses anything below a page.
+        Ok(size.max(UBLK_MIN_SEGMENT_SIZE))
+    }
+
     pub fn exists(&self) -> bool {
         self.config_path().is_file()
     }
@@ -153,6 +378,55 @@ impl Config {
         Ok(())
     }
 
+    /// Locks the repository for as long as the returned file is open.
+    /// Fails if another process, e.g. a running server, holds the lock.
+    pub fn lock(&self) -> Result<Flock<File>> {
+        let config_path = self.config_path();
+        let file = File::open(&config_path).with_context(|| {
+            format!("Failed to open {}", config_path.display())
+        })?;
+
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Result::Ok(file) => Ok(file),
+            Err((_, Errno::EWOULDBLOCK)) => bail!(
+                "The repository {} is in use, is the server running?",
+                self.repository.display()
+            ),
+            Err((_, err)) => Err(err).with_context(|| {
+                format!("Failed to lock {}", config_path.display())
+            }),
+        }
+    }
+
+    /// The socket a running server accepts commands on.
+    pub fn control_path(&self) -> PathBuf {
+        let mut path = self.repository.clone();
+        path.push("control.sock");
+        path
+    }
+
+    /// Holds the pid of a server running in the background.
+    pub fn pid_path(&self) -> PathBuf {
+        let mut path = self.repository.clone();
+        path.push("blkchnkr.pid");
+        path
+    }
+
+    /// The accesses of every chunk, see heatmap.
+    pub fn heatmap_path(&self) -> PathBuf {
+        let mut path = self.repository.clone();
+        path.push("heatmap");
+        path
+    }
+
+    /// Exists while the device is quiesced and all chunks have been
+    /// synced.
+    pub fn quiesced_path(&self) -> PathBuf {
+        let mut path = self.repository.clone();
+        path.push("quiesced");
+        path
+    }
+
     fn config_path(&self) -> PathBuf {
         let mut config = self.repository.clone();
         config.push("config");
@@ -199,6 +473,27 @@ impl Config {
         push_opt(&mut text, "fsuid", self.fsuid);
         push_opt(&mut text, "fsgid", self.fsgid);
         push_opt(&mut text, "direct-io", self.direct_io);
+        push_opt(&mut text, "cpus", self.cpus.as_ref());
+        push_opt(&mut text, "per-io-daemon", self.per_io_daemon);
+        push_opt(&mut text, "io-threads", self.io_threads);
+        push_opt(&mut text, "rotational", self.rotational);
+        push_opt(&mut text, "max-segments", self.max_segments);
+        push_opt(&mut text, "max-segment-size", self.max_segment_size);
+        push_opt(&mut text, "sqpoll", self.sqpoll);
+        push_opt(&mut text, "sqpoll-idle", self.sqpoll_idle);
+        push_opt(&mut text, "sqpoll-cpus", self.sqpoll_cpus.as_ref());
+        push_opt(&mut text, "iopoll", self.iopoll);
+        push_opt(&mut text, "io-timeout", self.io_timeout);
+        push_opt(&mut text, "hung-io-warning", self.hung_io_warning);
+        push_opt(&mut text, "shutdown-timeout", self.shutdown_timeout);
+        push_opt(&mut text, "retry-errnos", self.retry_errnos.as_ref());
+        push_opt(&mut text, "retry-attempts", self.retry_attempts);
+        push_opt(&mut text, "retry-backoff", self.retry_backoff);
+        push_opt(&mut text, "max-open-chunks", self.max_open_chunks);
+        push_opt(&mut text, "allocation", self.allocation);
+        push_opt(&mut text, "preallocate-ahead", self.preallocate_ahead);
+        push_opt(&mut text, "merge-io", self.merge_io);
+        push_opt(&mut text, "metrics", self.metrics.as_ref());
 
         text
     }
@@ -227,6 +522,27 @@ fn read_config_file(repository: PathBuf) -> Result<Config> {
     let mut fsuid: Option<u32> = None;
     let mut fsgid: Option<u32> = None;
     let mut direct_io: Option<bool> = None;
+    let mut cpus: Option<CpuList> = None;
+    let mut per_io_daemon: Option<bool> = None;
+    let mut io_threads: Option<u16> = None;
+    let mut rotational: Option<bool> = None;
+    let mut max_segments: Option<u16> = None;
+    let mut max_segment_size: Option<u32> = None;
+    let mut sqpoll: Option<bool> = None;
+    let mut sqpoll_idle: Option<u32> = None;
+    let mut sqpoll_cpus: Option<CpuList> = None;
+    let mut iopoll: Option<bool> = None;
+    let mut io_timeout: Option<u32> = None;
+    let mut hung_io_warning: Option<u32> = None;
+    let mut shutdown_timeout: Option<u32> = None;
+    let mut retry_errnos: Option<ErrnoList> = None;
+    let mut retry_attempts: Option<u32> = None;
+    let mut retry_backoff: Option<u32> = None;
+    let mut max_open_chunks: Option<u32> = None;
+    let mut allocation: Option<Allocation> = None;
+    let mut preallocate_ahead: Option<u32> = None;
+    let mut merge_io: Option<bool> = None;
+    let mut metrics: Option<MetricsListen> = None;
 
     for line in config_str.lines() {
         if line.starts_with("#") {
@@ -256,6 +572,86 @@ fn read_config_file(repository: PathBuf) -> Result<Config> {
             "direct-io" => {
                 direct_io = Some(parse_bool("direct-io", value)?)
             }
+            "cpus" => {
+                cpus =
+                    Some(value.parse().with_context(|| {
+                        anyhow!("Invalid value for cpus")
+                    })?)
+            }
+            "per-io-daemon" => {
+                per_io_daemon = Some(parse_bool("per-io-daemon", value)?)
+            }
+            "io-threads" => {
+                io_threads = Some(parse_num("io-threads", value)?)
+            }
+            "rotational" => {
+                rotational = Some(parse_bool("rotational", value)?)
+            }
+            "max-segments" => {
+                max_segments = Some(parse_num("max-segments", value)?)
+            }
+            "max-segment-size" => {
+                max_segment_size =
+                    Some(parse_num("max-segment-size", value)?)
+            }
+            "sqpoll" => sqpoll = Some(parse_bool("sqpoll", value)?),
+            "sqpoll-idle" => {
+                sqpoll_idle = Some(parse_num("sqpoll-idle", value)?)
+            }
+            "sqpoll-cpus" => {
+                sqpoll_cpus = Some(value.parse().with_context(|| {
+                    anyhow!("Invalid value for sqpoll-cpus")
+                })?)
+            }
+            "iopoll" => iopoll = Some(parse_bool("iopoll", value)?),
+            "io-timeout" => {
+                io_timeout = Some(parse_num("io-timeout", value)?)
+            }
+            "hung-io-warning" => {
+                hung_io_warning =
+                    Some(parse_num("hung-io-warning", value)?)
+            }
+            "shutdown-timeout" => {
+                shutdown_timeout =
+                    Some(parse_num("shutdown-timeout", value)?)
+            }
+            "retry-errnos" => {
+                retry_errnos = Some(value.parse().with_context(|| {
+                    anyhow!("Invalid value for retry-errnos")
+                })?)
+            }
+            "retry-attempts" => {
+                retry_attempts = Some(parse_num("retry-attempts", value)?)
+            }
+            "retry-backoff" => {
+                retry_backoff = Some(parse_num("retry-backoff", value)?)
+            }
+            "max-open-chunks" => {
+                let max = parse_num("max-open-chunks", value)?;
+                if max < MIN_OPEN_CHUNKS {
+                    bail!(
+                        "Invalid value for max-open-chunks, it must be at \
+                        least {}",
+                        MIN_OPEN_CHUNKS
+                    );
+                }
+                max_open_chunks = Some(max);
+            }
+            "allocation" => {
+                allocation = Some(value.parse().with_context(|| {
+                    anyhow!("Invalid value for allocation")
+                })?)
+            }
+            "preallocate-ahead" => {
+                preallocate_ahead =
+                    Some(parse_num("preallocate-ahead", value)?)
+            }
+            "merge-io" => merge_io = Some(parse_bool("merge-io", value)?),
+            "metrics" => {
+                metrics = Some(value.parse().with_context(|| {
+                    anyhow!("Invalid value for metrics")
+                })?)
+            }
             s => bail!("Unknown config setting \"{}\"", s),
         }
     }
@@ -275,6 +671,27 @@ fn read_config_file(repository: PathBuf) -> Result<Config> {
         fsuid,
         fsgid,
         direct_io,
+        cpus,
+        per_io_daemon,
+        io_threads,
+        rotational,
+        max_segments,
+        max_segment_size,
+        sqpoll,
+        sqpoll_idle,
+        sqpoll_cpus,
+        iopoll,
+        io_timeout,
+        hung_io_warning,
+        shutdown_timeout,
+        retry_errnos,
+        retry_attempts,
+        retry_backoff,
+        max_open_chunks,
+        allocation,
+        preallocate_ahead,
+        merge_io,
+        metrics,
         queue_limits: None,
     })
 }
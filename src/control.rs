@@ -0,0 +1,262 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use io_uring::opcode::PollAdd;
+use io_uring::types::Fd;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::eventfd::{EfdFlags, EventFd};
+
+use crate::config::Config;
+use crate::log::Level;
+use crate::runtime::Submitter;
+use crate::util::remove_stale_socket;
+
+/// How long a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A command sent to the control socket, one per connection and line.
+#[derive(Debug, Clone, Copy)]
+pub enum Request {
+    Status,
+    Stats,
+    SetLogLevel(Level),
+    Flush,
+    CloseIdleChunks,
+    Quiesce,
+    Resume,
+    Stop,
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+
+        let request = match words.next() {
+            Some("status") => Self::Status,
+            Some("stats") => Self::Stats,
+            Some("set-log-level") => {
+                let Some(level) = words.next() else {
+                    bail!("Missing the log level.");
+                };
+                Self::SetLogLevel(level.parse()?)
+            }
+            Some("flush") => Self::Flush,
+            Some("close-idle-chunks") => Self::CloseIdleChunks,
+            Some("quiesce") => Self::Quiesce,
+            Some("resume") => Self::Resume,
+            Some("stop") => Self::Stop,
+            Some(command) => bail!("Unknown command {}.", command),
+            None => bail!("Empty request."),
+        };
+
+        if words.next().is_some() {
+            bail!("Too many arguments.");
+        }
+
+        Ok(request)
+    }
+}
+
+/// The control socket of a running server. The socket is removed when
+/// this is dropped.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn bind(config: &Config) -> Result<Self> {
+        let path = config.control_path();
+
+        remove_stale_socket(&path);
+
+        let listener = UnixListener::bind(&path).with_context(|| {
+            format!("Failed to listen for commands on {}", path.display())
+        })?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self { listener, path })
+    }
+
+    /// Returns the next pending connection, if there's one.
+    pub fn accept(&self) -> Result<Option<UnixStream>> {
+        match self.listener.accept() {
+            Ok((conn, _)) => {
+                conn.set_nonblocking(false)?;
+                conn.set_read_timeout(Some(READ_TIMEOUT))?;
+                Ok(Some(conn))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn read_request(conn: &mut UnixStream) -> Result<Request> {
+    let mut line = String::new();
+    BufReader::new(conn.take(4096)).read_line(&mut line)?;
+
+    Request::parse(&line)
+}
+
+/// The response is a line with either "ok" or "error: <message>",
+/// followed by the output of the command.
+pub fn respond(conn: &mut UnixStream, result: Result<String>) {
+    let response = match result {
+        Ok(output) => format!("ok\n{}", output),
+        Err(err) => format!("error: {}\n", err),
+    };
+
+    _ = conn.write_all(response.as_bytes());
+}
+
+/// Sends the command to the server running at the repository and returns
+/// its output.
+pub fn send(config: &Config, command: &str) -> Result<String> {
+    let path = config.control_path();
+    let mut conn = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "Failed to connect to {}. Is the server running?",
+            path.display()
+        )
+    })?;
+
+    conn.write_all(format!("{}\n", command).as_bytes())?;
+
+    let mut response = String::new();
+    conn.read_to_string(&mut response)?;
+
+    let Some((status, output)) = response.split_once('\n') else {
+        bail!("Received an invalid response.");
+    };
+
+    if let Some(message) = status.strip_prefix("error: ") {
+        bail!("{}", message);
+    }
+
+    if status != "ok" {
+        bail!("Received an invalid response.");
+    }
+
+    Ok(output.to_owned())
+}
+
+/// What a worker does on behalf of the control socket.
+#[derive(Debug, Clone, Copy)]
+pub enum WorkerCommand {
+    /// Syncs the open chunks.
+    Flush,
+
+    /// Closes the open chunks which no request is using.
+    CloseIdleChunks,
+}
+
+/// The command and where to send the number of affected chunks to.
+type Message = (WorkerCommand, Sender<usize>);
+
+/// Sends commands to a worker. The worker polls the eventfd so it's
+/// woken up even if it's otherwise idle.
+pub struct WorkerControl {
+    sender: Sender<Message>,
+    event: Arc<EventFd>,
+}
+
+/// The worker's end of `WorkerControl`.
+pub struct WorkerInbox {
+    receiver: Receiver<Message>,
+    event: Arc<EventFd>,
+}
+
+pub fn worker_channel() -> Result<(WorkerControl, WorkerInbox)> {
+    let event = Arc::new(EventFd::from_flags(
+        EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC,
+    )?);
+    let (sender, receiver) = mpsc::channel();
+
+    Ok((
+        WorkerControl {
+            sender,
+            event: event.clone(),
+        },
+        WorkerInbox { receiver, event },
+    ))
+}
+
+impl WorkerControl {
+    /// Returns a receiver of the command's result.
+    pub fn send(&self, command: WorkerCommand) -> Result<Receiver<usize>> {
+        let (sender, receiver) = mpsc::channel();
+
+        self.sender
+            .send((command, sender))
+            .map_err(|_| anyhow!("The worker has exited."))?;
+        self.event.write(1)?;
+
+        Ok(receiver)
+    }
+}
+
+impl WorkerInbox {
+    /// Waits for the next command. Returns None once the server has
+    /// dropped the other end.
+    pub async fn next(
+        &mut self,
+        submitter: &mut Submitter,
+    ) -> Option<(WorkerCommand, Sender<usize>)> {
+        loop {
+            // Reset the counter before checking for messages so that
+            // none is missed.
+            match self.event.read() {
+                Ok(_) | Err(Errno::EAGAIN) => {}
+                Err(err) => {
+                    error!(
+                        "Failed to read the worker's eventfd err={}",
+                        err
+                    );
+                    return None;
+                }
+            }
+
+            match self.receiver.try_recv() {
+                Ok(message) => return Some(message),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let fd = Fd(self.event.as_raw_fd());
+            let sqe = PollAdd::new(fd, libc::POLLIN as _).build();
+
+            match submitter.submit_entry(sqe) {
+                Ok(waiter) => _ = waiter.await,
+                Err(err) => {
+                    error!(
+                        "Failed to poll the worker's eventfd err={}",
+                        err
+                    );
+                    return None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+use anyhow::{Context, Result, bail};
+use io_uring::types::Fixed;
+
+use crate::bindings::ublksrv_ctrl_dev_info;
+use crate::config::Config;
+use crate::sqes::send_get_info_cmd;
+use crate::types::Ring128;
+
+pub const UBLK_CONTROL_FD_IDX: Fixed = Fixed(0);
+
+#[inline(always)]
+pub fn open_ublk_ctrl() -> Result<OwnedFd> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/ublk-control");
+
+    match file {
+        Ok(file) => Ok(file.into()),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            bail!(
+                "Unable to open /dev/ublk-control: {}. Running as a \
+                non-root user requires a udev rule which makes it \
+                accessible to the current user (e.g. MODE=\"0666\").",
+                err
+            )
+        }
+        Err(err) => Err(err).context(
+            "Unable to open /dev/ublk-control. Make sure the kernel \
+            module ublk_drv is loaded and accessible to the current user.",
+        ),
+    }
+}
+
+pub fn create_ctrl_ring(fd: RawFd) -> Result<Ring128> {
+    let ring = Ring128::builder()
+        .setup_coop_taskrun()
+        .setup_single_issuer()
+        .build(8)?;
+
+    // UBLK_CONTROL_FD_IDX
+    ring.submitter().register_files(&[fd])?;
+
+    Ok(ring)
+}
+
+/// Asks the driver about the device of the given repository.
+pub fn dev_info_for(config: &Config) -> Result<ublksrv_ctrl_dev_info> {
+    let Some(dev_id) = config.dev_id else {
+        bail!(
+            "The repository doesn't have a fixed device ID (--dev-id) so \
+            the device cannot be looked up."
+        );
+    };
+
+    let fd = open_ublk_ctrl()?;
+    let mut ring = create_ctrl_ring(fd.as_raw_fd())?;
+
+    send_get_info_cmd(dev_id, &mut ring, UBLK_CONTROL_FD_IDX)
+}
@@ -0,0 +1,180 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{Context, Error, Result, bail};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::libc;
+use nix::unistd::{self, ForkResult};
+
+use crate::config::Config;
+
+/// The server running in the background. The pidfile is removed when this
+/// is dropped.
+pub struct Daemon {
+    /// Where to report whether the device came up, the parent waits for
+    /// it before exiting.
+    parent: Option<File>,
+
+    /// Locked for as long as the server runs.
+    _pidfile: Flock<File>,
+    pid_path: PathBuf,
+}
+
+/// Forks into the background. Has to be called before any threads are
+/// started as only the calling thread survives the fork.
+///
+/// The parent doesn't return, it waits for the child to report whether
+/// the device came up and exits with 0 if it did, 1 otherwise. The output
+/// of the child goes to the log file if there's one, /dev/null otherwise.
+pub fn daemonize(
+    config: &Config,
+    log_file: Option<&Path>,
+) -> Result<Daemon> {
+    let pid_path = config.pid_path();
+    let mut pidfile = lock_pidfile(&pid_path)?;
+
+    let output = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| {
+                format!("Failed to open the log file {}", path.display())
+            })?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+
+    let (reader, writer) = unistd::pipe()?;
+
+    match unsafe { unistd::fork()? } {
+        // The lock is shared with the child. The parent exits without
+        // running any destructors so it stays locked.
+        ForkResult::Parent { .. } => {
+            drop(writer);
+            wait_for_child(File::from(reader), log_file)
+        }
+        ForkResult::Child => {
+            drop(reader);
+            unistd::setsid()?;
+
+            for (from, to) in [
+                (input.as_raw_fd(), libc::STDIN_FILENO),
+                (output.as_raw_fd(), libc::STDOUT_FILENO),
+                (output.as_raw_fd(), libc::STDERR_FILENO),
+            ] {
+                if unsafe { libc::dup2(from, to) } < 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+            }
+
+            pidfile
+                .set_len(0)
+                .and_then(|_| writeln!(pidfile, "{}", process::id()))
+                .with_context(|| {
+                    format!("Failed to write {}", pid_path.display())
+                })?;
+
+            Ok(Daemon {
+                parent: Some(File::from(writer)),
+                _pidfile: pidfile,
+                pid_path,
+            })
+        }
+    }
+}
+
+/// Locks the pidfile, creating it if there's none. Fails if another
+/// server holds the lock, a stale pidfile is simply taken over.
+fn lock_pidfile(pid_path: &Path) -> Result<Flock<File>> {
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(pid_path)
+            .with_context(|| {
+                format!("Failed to open {}", pid_path.display())
+            })?;
+
+        let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock)
+        {
+            Ok(file) => file,
+            Err((_, Errno::EWOULDBLOCK)) => {
+                let pid = fs::read_to_string(pid_path).unwrap_or_default();
+                bail!(
+                    "The server is already running (pid {}).",
+                    pid.trim()
+                );
+            }
+            Err((_, err)) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to lock {}", pid_path.display())
+                });
+            }
+        };
+
+        // The previous server removes the pidfile before it lets go of
+        // the lock, the lock might be on a file which is gone by now.
+        let locked = file.metadata()?;
+        if fs::metadata(pid_path).is_ok_and(|current| {
+            current.dev() == locked.dev() && current.ino() == locked.ino()
+        }) {
+            return Ok(file);
+        }
+    }
+}
+
+fn wait_for_child(mut child: File, log_file: Option<&Path>) -> ! {
+    let mut report = String::new();
+    _ = child.read_to_string(&mut report);
+
+    if report == "ok\n" {
+        process::exit(0);
+    }
+
+    if report.is_empty() {
+        match log_file {
+            Some(path) => eprintln!(
+                "Error: The server exited before the device came up, see \
+                {}.",
+                path.display()
+            ),
+            None => eprintln!(
+                "Error: The server exited before the device came up."
+            ),
+        }
+    } else {
+        eprintln!("Error: {}", report);
+    }
+
+    process::exit(1);
+}
+
+impl Daemon {
+    /// The device is live, lets the parent exit successfully.
+    pub fn ready(&mut self) {
+        if let Some(mut parent) = self.parent.take() {
+            _ = parent.write_all(b"ok\n");
+        }
+    }
+
+    /// The server failed before the device came up.
+    pub fn failed(&mut self, err: &Error) {
+        if let Some(mut parent) = self.parent.take() {
+            _ = write!(parent, "{:#}", err);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.pid_path);
+    }
+}
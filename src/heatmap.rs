@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// How often the heatmap is written to the repository.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// The width of the map of the address space, in cells.
+const WIDTH: usize = 64;
+const MAX_ROWS: usize = 16;
+
+/// From cold to hot, a space is a chunk which has never been accessed.
+const SHADES: &[u8] = b".:-=+*#%@";
+
+/// The accesses of a chunk.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChunkAccess {
+    pub reads: u64,
+    pub writes: u64,
+
+    /// In seconds since the Unix epoch.
+    pub last_access: u64,
+}
+
+impl ChunkAccess {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn merge(&mut self, other: &ChunkAccess) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.last_access = self.last_access.max(other.last_access);
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The accesses of all chunks since the repository was created, keyed by
+/// the chunk's number.
+#[derive(Debug, Default, Clone)]
+pub struct Heatmap {
+    chunks: BTreeMap<u32, ChunkAccess>,
+}
+
+impl Heatmap {
+    /// Returns an empty heatmap if none has been persisted yet.
+    pub fn load(config: &Config) -> Result<Self> {
+        let path = config.heatmap_path();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        let mut chunks = BTreeMap::new();
+
+        for line in contents.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            let values = line
+                .split_whitespace()
+                .map(|value| value.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>();
+
+            let Ok(&[file_num, reads, writes, last_access]) =
+                values.as_deref()
+            else {
+                bail!("Invalid line in {}: {}", path.display(), line);
+            };
+
+            chunks.insert(
+                file_num as u32,
+                ChunkAccess {
+                    reads,
+                    writes,
+                    last_access,
+                },
+            );
+        }
+
+        Ok(Self { chunks })
+    }
+
+    /// Replaces the persisted heatmap atomically.
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let mut out = String::from("# chunk reads writes last_access\n");
+
+        for (file_num, access) in &self.chunks {
+            _ = writeln!(
+                out,
+                "{} {} {} {}",
+                file_num, access.reads, access.writes, access.last_access
+            );
+        }
+
+        let path = config.heatmap_path();
+        let tmp = path.with_extension("tmp");
+
+        fs::write(&tmp, out)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn merge(&mut self, chunks: &BTreeMap<u32, ChunkAccess>) {
+        for (file_num, access) in chunks {
+            self.chunks.entry(*file_num).or_default().merge(access);
+        }
+    }
+
+    /// The `top` hottest chunks and a map of the whole address space.
+    pub fn render(&self, config: &Config, top: usize) -> String {
+        let nr_chunks = config.size.div_ceil(config.chunk_size) as usize;
+        let mut out = String::with_capacity(4096);
+
+        _ = writeln!(
+            out,
+            "{} of {} chunks accessed",
+            self.chunks.len(),
+            nr_chunks
+        );
+
+        let mut hottest: Vec<_> = self.chunks.iter().collect();
+        hottest
+            .sort_by_key(|(_, access)| std::cmp::Reverse(access.total()));
+        hottest.truncate(top);
+
+        if !hottest.is_empty() {
+            _ = writeln!(out);
+            _ = writeln!(
+                out,
+                "{:>8}{:>12}{:>14}{:>14}  last access",
+                "chunk", "offset", "reads", "writes"
+            );
+        }
+
+        let now = now();
+        for (file_num, access) in hottest {
+            _ = writeln!(
+                out,
+                "{:>8}{:>12}{:>14}{:>14}  {} ago",
+                file_num,
+                format_bytes(*file_num as u64 * config.chunk_size),
+                access.reads,
+                access.writes,
+                format_age(now.saturating_sub(access.last_access))
+            );
+        }
+
+        self.render_map(&mut out, config, nr_chunks);
+
+        out
+    }
+
+    /// Every cell covers the same number of chunks and is shaded by their
+    /// accesses relative to the hottest cell.
+    fn render_map(
+        &self,
+        out: &mut String,
+        config: &Config,
+        nr_chunks: usize,
+    ) {
+        let per_cell = nr_chunks.div_ceil(WIDTH * MAX_ROWS).max(1);
+        let nr_cells = nr_chunks.div_ceil(per_cell);
+
+        let mut cells = vec![None::<u64>; nr_cells];
+        for (file_num, access) in &self.chunks {
+            if let Some(cell) =
+                cells.get_mut(*file_num as usize / per_cell)
+            {
+                *cell = Some(cell.unwrap_or(0) + access.total());
+            }
+        }
+
+        let max = cells.iter().flatten().copied().max().unwrap_or(0);
+
+        _ = writeln!(out);
+        _ = writeln!(
+            out,
+            "Each cell is {} ({} chunks), from cold to hot: {}",
+            format_bytes(per_cell as u64 * config.chunk_size),
+            per_cell,
+            String::from_utf8_lossy(SHADES)
+        );
+
+        for (row, cells) in cells.chunks(WIDTH).enumerate() {
+            let offset =
+                (row * WIDTH * per_cell) as u64 * config.chunk_size;
+            _ = write!(out, "{:>8} |", format_bytes(offset));
+
+            for cell in cells {
+                let shade = match cell {
+                    None => b' ',
+                    Some(total) => {
+                        let idx = (*total as u128
+                            * (SHADES.len() - 1) as u128)
+                            .checked_div(max as u128)
+                            .unwrap_or(0);
+                        SHADES[idx as usize]
+                    }
+                };
+                out.push(shade as char);
+            }
+
+            _ = writeln!(out, "|");
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] =
+        [("T", 1 << 40), ("G", 1 << 30), ("M", 1 << 20)];
+
+    for (unit, size) in UNITS {
+        if bytes >= size {
+            return format!("{:.1}{}", bytes as f64 / size as f64, unit);
+        }
+    }
+
+    format!("{}", bytes)
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Periodically writes the accesses counted by the workers, on top of the
+/// ones persisted by previous runs, to the repository.
+pub struct Persister {
+    config: Config,
+    base: Heatmap,
+    metrics: Arc<Metrics>,
+
+    /// Serializes the periodic and the final write.
+    lock: Mutex<()>,
+}
+
+impl Persister {
+    pub fn start(
+        config: &Config,
+        metrics: Arc<Metrics>,
+    ) -> Result<Arc<Self>> {
+        let persister = Arc::new(Self {
+            config: config.clone(),
+            base: Heatmap::load(config)?,
+            metrics,
+            lock: Mutex::new(()),
+        });
+
+        let this = persister.clone();
+        thread::Builder::new()
+            .name("blkchnkr-heatmap".into())
+            .spawn(move || {
+                loop {
+                    thread::sleep(INTERVAL);
+
+                    if let Err(err) = this.persist() {
+                        warn!(
+                            "Failed to persist the heatmap. Err: {}",
+                            err
+                        );
+                    }
+                }
+            })
+            .context("Failed to start the heatmap thread.")?;
+
+        Ok(persister)
+    }
+
+    pub fn persist(&self) -> Result<()> {
+        let _lock =
+            self.lock.lock().unwrap_or_else(|err| err.into_inner());
+
+        let mut heatmap = self.base.clone();
+        heatmap.merge(&self.metrics.chunk_accesses());
+        heatmap.save(&self.config)
+    }
+}
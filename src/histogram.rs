@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+
+/// Each power of two is split into this many linear buckets, which bounds
+/// the error of a value to about 6%.
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+
+/// Covers values up to 2^41 ns (about 36 minutes), larger values end up in
+/// the last bucket.
+const NR_BUCKETS: usize = (41 - SUB_BITS as usize) * SUB_BUCKETS;
+
+/// A log-linear histogram of durations in the spirit of HdrHistogram. The
+/// buckets are allocated up front so recording a value is a single atomic
+/// increment.
+pub struct Histogram {
+    counts: Box<[AtomicU64]>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: (0..NR_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket(nanos)].fetch_add(1, Relaxed);
+    }
+
+    /// Adds the counts to the snapshot.
+    pub fn merge_into(&self, snapshot: &mut Snapshot) {
+        for (total, count) in snapshot.counts.iter_mut().zip(&self.counts)
+        {
+            *total += count.load(Relaxed);
+        }
+    }
+}
+
+/// The counts of one or more histograms at a point in time.
+pub struct Snapshot {
+    counts: Vec<u64>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; NR_BUCKETS],
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The value at the quantile (e.g. 0.99), rounded up to the end of its
+    /// bucket.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let total = self.count();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank =
+            ((total as f64 * quantile).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+
+        for (idx, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_end(idx));
+            }
+        }
+
+        Duration::from_nanos(bucket_end(NR_BUCKETS - 1))
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    let magnitude = 63 - value.leading_zeros();
+    let shift = magnitude - SUB_BITS;
+    let sub = (value >> shift) as usize & (SUB_BUCKETS - 1);
+
+    ((shift as usize + 1) * SUB_BUCKETS + sub).min(NR_BUCKETS - 1)
+}
+
+/// The largest value which falls into the bucket.
+fn bucket_end(idx: usize) -> u64 {
+    if idx < SUB_BUCKETS {
+        return idx as u64;
+    }
+
+    let shift = (idx / SUB_BUCKETS - 1) as u32;
+    let sub = (idx % SUB_BUCKETS) as u64;
+
+    ((SUB_BUCKETS as u64 + sub + 1) << shift) - 1
+}
@@ -1,22 +1,29 @@
 use std::alloc::{self, Layout, handle_alloc_error};
 
 use anyhow::{Context, Ok, Result};
+use nix::libc;
 
 use crate::util::page_size;
 
 pub struct IoBuffers {
     layout: Layout,
     elem_size: usize,
+    nr_slots: u16,
     ptr: *mut u8,
+
+    /// Whether the buffers have been registered with the ring and can be
+    /// used with the fixed variants of read and write. The buffer index
+    /// is the slot.
+    registered: bool,
 }
 
 impl IoBuffers {
-    pub fn new(max_io_buf_bytes: u32, queue_depth: u16) -> Result<Self> {
+    pub fn new(max_io_buf_bytes: u32, nr_slots: u16) -> Result<Self> {
         let page_size = page_size()?;
         let elem_size = (max_io_buf_bytes as usize)
             .checked_next_multiple_of(page_size)
             .context("Invalid elem size.")?;
-        let size = elem_size * queue_depth as usize;
+        let size = elem_size * nr_slots as usize;
         let layout = Layout::from_size_align(size, page_size)?;
 
         let ptr = unsafe { alloc::alloc(layout) };
@@ -27,24 +34,45 @@ impl IoBuffers {
         Ok(Self {
             layout,
             elem_size,
+            nr_slots,
             ptr,
+            registered: false,
         })
     }
 
+    /// One iovec per slot to be registered with the ring.
+    pub fn iovecs(&self) -> Vec<libc::iovec> {
+        (0..self.nr_slots)
+            .map(|slot| libc::iovec {
+                iov_base: self.get_buf(slot).cast(),
+                iov_len: self.elem_size,
+            })
+            .collect()
+    }
+
+    pub fn set_registered(&mut self, registered: bool) {
+        self.registered = registered;
+    }
+
+    #[inline(always)]
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
     #[inline(always)]
-    pub fn get_buf_addr(&self, tag: u8) -> u64 {
-        self.get_buf(tag).addr() as _
+    pub fn get_buf_addr(&self, slot: u16) -> u64 {
+        self.get_buf(slot).addr() as _
     }
 
     #[inline(always)]
     pub fn get_buf_with_offsets(
         &mut self,
-        tag: u8,
+        slot: u16,
         buf_offset: u32,
         curr_offset: u32,
     ) -> *mut u8 {
         unsafe {
-            self.get_buf_with_offset(tag, buf_offset)
+            self.get_buf_with_offset(slot, buf_offset)
                 .add(curr_offset as usize)
         }
     }
@@ -52,15 +80,15 @@ impl IoBuffers {
     #[inline(always)]
     fn get_buf_with_offset(
         &mut self,
-        tag: u8,
+        slot: u16,
         buf_offset: u32,
     ) -> *mut u8 {
-        unsafe { self.get_buf(tag).add((buf_offset as usize) << 9) }
+        unsafe { self.get_buf(slot).add((buf_offset as usize) << 9) }
     }
 
     #[inline(always)]
-    fn get_buf(&self, tag: u8) -> *mut u8 {
-        unsafe { self.ptr.add(self.elem_size * tag as usize) }
+    fn get_buf(&self, slot: u16) -> *mut u8 {
+        unsafe { self.ptr.add(self.elem_size * slot as usize) }
     }
 }
 
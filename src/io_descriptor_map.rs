@@ -58,6 +58,38 @@ impl IoDescriptorMap {
             len: len.into(),
         })
     }
+
+    /// A map which isn't backed by a device and is filled in via `set`
+    /// instead, see replay.
+    pub fn anonymous(queue_depth: u16) -> Result<Self> {
+        let len = NonZero::new(len(queue_depth)?)
+            .ok_or_else(|| anyhow!("Incorrect len size"))?;
+
+        let ptr = unsafe {
+            mman::mmap_anonymous(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE,
+            )?
+            .as_ptr()
+        };
+
+        Ok(Self {
+            ptr,
+            len: len.into(),
+        })
+    }
+
+    /// Only valid for anonymous maps, the device's map is read only.
+    pub fn set(&mut self, index: usize, desc: ublksrv_io_desc) {
+        unsafe {
+            let ptr: *mut ublksrv_io_desc =
+                self.ptr.add(index * size_of::<ublksrv_io_desc>()).cast();
+
+            ptr.write(desc);
+        }
+    }
 }
 
 impl Index<usize> for IoDescriptorMap {
@@ -2,122 +2,478 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::os::fd::RawFd;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::bindings::ublksrv_ctrl_dev_info;
+use crate::chunk_cache::ChunkCache;
+use crate::chunk_opener::ChunkOpener;
+use crate::chunk_registry::ChunkRegistry;
 use crate::config::Config;
+use crate::control::{WorkerCommand, WorkerInbox};
 use crate::io_buffers::IoBuffers;
 use crate::io_descriptor_map::IoDescriptorMap;
-use crate::runtime::Runtime;
+use crate::merge::{Batcher, Merger};
+use crate::metrics::WorkerMetrics;
+use crate::replay::Replay;
+use crate::retry::RetryPolicy;
+use crate::runtime::{Runtime, Submitter, Waiter};
+use crate::sqes::create_flush_sqe;
 use crate::task::Task;
+use crate::trace::{Trace, TraceBuffer};
 use crate::types::Ring;
+use crate::watchdog::{InFlight, watchdog};
 
 use anyhow::Result;
+use io_uring::types::Timespec;
+use nix::libc;
 
 pub const UBLKC_FD_IDX: u32 = 0;
 
+/// The most requests a worker can serve. A request might take up to 3
+/// entries and a ring has at most 32768 (IORING_MAX_ENTRIES).
+pub const MAX_SLOTS: usize = 32768 / 3;
+
 fn create_ring(
-    dev_info: &ublksrv_ctrl_dev_info,
-    fd: RawFd,
+    config: &Config,
+    worker_id: usize,
+    nr_slots: u32,
+    fd: Option<RawFd>,
 ) -> Result<Ring> {
-    let ring = Ring::builder()
+    let mut builder = Ring::builder();
+
+    builder
         // We might need to split the incoming requests into multiple IO
         // requests.
-        .setup_cqsize(dev_info.queue_depth as u32 * 3)
+        .setup_cqsize(nr_slots * 3)
         .setup_single_issuer()
-        .setup_coop_taskrun()
-        .setup_defer_taskrun()
-        .build(dev_info.queue_depth as u32 * 3)?;
+        .setup_coop_taskrun();
 
-    // Ought to be enough for anybody
-    ring.submitter().register_files_sparse(400_000)?;
+    if config.sqpoll() {
+        builder.setup_sqpoll(config.sqpoll_idle());
+
+        if let Some(cpu) = config.sqpoll_cpu(worker_id) {
+            builder.setup_sqpoll_cpu(cpu);
+        }
+    } else if !config.iopoll() {
+        // Deferred task work only runs while waiting for completions,
+        // which the worker doesn't do while busy polling.
+        builder.setup_defer_taskrun();
+    }
 
-    // UBLKC_FD_IDX = /dev/ublkcN
-    ring.submitter().register_files_update(0, &[fd])?;
+    let ring = builder.build(nr_slots * 3)?;
+
+    // The chunks follow /dev/ublkcN.
+    ring.submitter()
+        .register_files_sparse(config.max_open_chunks() + 1)?;
+
+    // UBLKC_FD_IDX = /dev/ublkcN, stays empty when replaying.
+    if let Some(fd) = fd {
+        ring.submitter().register_files_update(0, &[fd])?;
+    }
 
     Ok(ring)
 }
 
+/// A ring only for reads and writes of chunks, which is busy polled for
+/// completions.
+fn create_poll_ring(config: &Config, nr_slots: u32) -> Result<Ring> {
+    let ring = Ring::builder()
+        .setup_cqsize(nr_slots * 3)
+        .setup_single_issuer()
+        .setup_iopoll()
+        .build(nr_slots * 3)?;
+
+    // Mirrors the files registered with the main ring.
+    ring.submitter()
+        .register_files_sparse(config.max_open_chunks() + 1)?;
+
+    Ok(ring)
+}
+
+/// Registers the IO buffers with the rings. This is just an optimization so
+/// if it fails, the worker falls back to regular reads and writes.
+fn register_buffers(rings: &[&Ring], bufs: &mut IoBuffers) {
+    let iovecs = bufs.iovecs();
+
+    // Safety: The buffers outlive the rings, see IoWorker.
+    let result = rings.iter().try_for_each(|ring| unsafe {
+        ring.submitter().register_buffers(&iovecs)
+    });
+
+    match result {
+        Ok(()) => bufs.set_registered(true),
+        Err(err) if err.raw_os_error() == Some(libc::ENOMEM) => {
+            warn!(
+                "Unable to register IO buffers, the limit on locked memory \
+                (RLIMIT_MEMLOCK) is too low. Falling back to unregistered \
+                buffers. Err: {}",
+                err
+            );
+        }
+        Err(err) => {
+            warn!(
+                "Unable to register IO buffers. Falling back to \
+                unregistered buffers. Err: {}",
+                err
+            );
+        }
+    }
+}
+
+/// A tag of a hardware queue served by a worker.
+#[derive(Debug, Clone, Copy)]
+pub struct Slot {
+    pub queue_id: u16,
+    pub tag: u16,
+}
+
 pub struct IoWorker {
-    queue_id: usize,
     config: Config,
-    dev_info: ublksrv_ctrl_dev_info,
+    slots: Box<[Slot]>,
+
+    // Declared before the buffers so that the ring is dropped first.
+    runtime: Runtime,
 
-    descriptor_map: Rc<RefCell<IoDescriptorMap>>,
+    descriptor_maps: HashMap<u16, Rc<RefCell<IoDescriptorMap>>>,
     bufs: Rc<RefCell<IoBuffers>>,
-    file_indexes: Rc<RefCell<HashMap<u32, u32>>>,
+    chunks: Rc<RefCell<ChunkCache>>,
+    opener: Rc<ChunkOpener>,
+    io_timeout: Option<Rc<Timespec>>,
+    inflight: Rc<RefCell<InFlight>>,
+    retry: Rc<RetryPolicy>,
+    merger: Option<Rc<Merger>>,
+    metrics: Arc<WorkerMetrics>,
+    inbox: Option<WorkerInbox>,
+    trace: Option<Rc<TraceBuffer>>,
 
-    runtime: Runtime,
+    /// The requests come from the trace rather than the device.
+    replay: Option<Rc<Replay>>,
 }
 
 impl IoWorker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        queue_id: usize,
+        worker_id: usize,
+        slots: Box<[Slot]>,
         config: Config,
         dev_info: ublksrv_ctrl_dev_info,
         ublkc_dev_fd: RawFd,
+        registry: Arc<ChunkRegistry>,
+        metrics: Arc<WorkerMetrics>,
+        inbox: WorkerInbox,
+        trace: Option<Arc<Trace>>,
     ) -> Result<Self> {
-        let descriptor_map = IoDescriptorMap::new(
-            queue_id,
-            ublkc_dev_fd,
-            dev_info.queue_depth,
-        )?;
-        let bufs = IoBuffers::new(
+        let mut descriptor_maps = HashMap::new();
+
+        for slot in &slots {
+            if descriptor_maps.contains_key(&slot.queue_id) {
+                continue;
+            }
+
+            let descriptor_map = IoDescriptorMap::new(
+                slot.queue_id as usize,
+                ublkc_dev_fd,
+                dev_info.queue_depth,
+            )?;
+            descriptor_maps.insert(
+                slot.queue_id,
+                Rc::new(RefCell::new(descriptor_map)),
+            );
+        }
+
+        Self::build(
+            worker_id,
+            slots,
+            config,
+            descriptor_maps,
             dev_info.max_io_buf_bytes,
-            dev_info.queue_depth,
-        )?;
+            Some(ublkc_dev_fd),
+            registry,
+            metrics,
+        )
+        .map(|worker| Self {
+            inbox: Some(inbox),
+            trace: trace.map(|trace| Rc::new(TraceBuffer::new(trace))),
+            ..worker
+        })
+    }
+
+    /// A worker with `depth` slots of queue 0 which replays the trace
+    /// without a device.
+    pub fn replay(
+        config: Config,
+        depth: u16,
+        max_io_buf_bytes: u32,
+        registry: Arc<ChunkRegistry>,
+        metrics: Arc<WorkerMetrics>,
+        replay: Rc<Replay>,
+    ) -> Result<Self> {
+        let slots =
+            (0..depth).map(|tag| Slot { queue_id: 0, tag }).collect();
 
-        let ring = create_ring(&dev_info, ublkc_dev_fd)?;
-        let runtime = Runtime::new(ring);
+        let mut descriptor_maps = HashMap::new();
+        descriptor_maps.insert(
+            0,
+            Rc::new(RefCell::new(IoDescriptorMap::anonymous(depth)?)),
+        );
+
+        Self::build(
+            0,
+            slots,
+            config,
+            descriptor_maps,
+            max_io_buf_bytes,
+            None,
+            registry,
+            metrics,
+        )
+        .map(|worker| Self {
+            replay: Some(replay),
+            ..worker
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build(
+        worker_id: usize,
+        slots: Box<[Slot]>,
+        config: Config,
+        descriptor_maps: HashMap<u16, Rc<RefCell<IoDescriptorMap>>>,
+        max_io_buf_bytes: u32,
+        ublkc_dev_fd: Option<RawFd>,
+        registry: Arc<ChunkRegistry>,
+        metrics: Arc<WorkerMetrics>,
+    ) -> Result<Self> {
+        let mut bufs =
+            IoBuffers::new(max_io_buf_bytes, slots.len() as u16)?;
+
+        let nr_slots = slots.len() as u32;
+        let ring =
+            create_ring(&config, worker_id, nr_slots, ublkc_dev_fd)?;
+        let poll_ring = if config.iopoll() {
+            Some(create_poll_ring(&config, nr_slots)?)
+        } else {
+            None
+        };
+
+        let mut rings = vec![&ring];
+        rings.extend(poll_ring.as_ref());
+        register_buffers(&rings, &mut bufs);
+
+        let opener = Rc::new(ChunkOpener::new(
+            &config,
+            &ring,
+            registry.clone(),
+            metrics.clone(),
+        )?);
+        let runtime = Runtime::new(ring, poll_ring);
+
+        let io_timeout = config
+            .io_timeout()
+            .map(|timeout| Rc::new(Timespec::from(timeout)));
+        let inflight = Rc::new(RefCell::new(InFlight::new(slots.len())));
+        let retry = Rc::new(RetryPolicy::new(&config));
+        let merger = config.merge_io().then(|| Rc::new(Merger::default()));
+        let chunks = Rc::new(RefCell::new(ChunkCache::new(
+            config.max_open_chunks() as usize,
+            UBLKC_FD_IDX + 1,
+            registry,
+        )));
 
         Ok(Self {
-            queue_id,
             config,
-            dev_info,
+            slots,
 
-            descriptor_map: Rc::new(RefCell::new(descriptor_map)),
+            descriptor_maps,
             bufs: Rc::new(RefCell::new(bufs)),
-            file_indexes: Rc::new(RefCell::new(HashMap::with_capacity(
-                1024,
-            ))),
+            chunks,
+            opener,
+            io_timeout,
+            inflight,
+            retry,
+            merger,
+            metrics,
+            inbox: None,
+            trace: None,
+            replay: None,
 
             runtime,
         })
     }
 
+    /// Serves requests until the device goes away. The chunks are synced
+    /// by the caller once all workers are done.
     pub fn work(&mut self) -> Result<()> {
         self.spawn_tasks()?;
-        self.runtime.run()
+        self.runtime.run()?;
+        self.metrics.log_retries();
+        self.metrics.log_merge_ratio();
+        if let Some(trace) = &self.trace {
+            trace.flush();
+        }
+
+        Ok(())
     }
 
     fn spawn_tasks(&mut self) -> Result<()> {
         debug!("spawning tasks");
 
-        for tag in 0..self.dev_info.queue_depth as u8 {
-            let queue_id = self.queue_id;
+        for (slot_idx, slot) in self.slots.iter().enumerate() {
+            let slot_idx = slot_idx as u16;
+            let Slot { queue_id, tag } = *slot;
             let config = self.config.clone();
-            let descs = self.descriptor_map.clone();
+            let descs = self.descriptor_maps[&queue_id].clone();
             let bufs = self.bufs.clone();
-            let file_indexes = self.file_indexes.clone();
+            let chunks = self.chunks.clone();
+            let opener = self.opener.clone();
+            let io_timeout = self.io_timeout.clone();
+            let inflight = self.inflight.clone();
+            let retry = self.retry.clone();
+            let merger = self.merger.clone();
+            let metrics = self.metrics.clone();
+            let trace = self.trace.clone();
+            let replay = self.replay.clone();
 
-            self.runtime.spawn(tag, |submitter| async move {
+            self.runtime.spawn(slot_idx, |submitter| async move {
                 let mut t = Task::new(
-                    submitter,
-                    queue_id,
-                    config,
-                    tag,
-                    descs,
-                    bufs,
-                    file_indexes,
+                    submitter, queue_id, config, tag, slot_idx, descs,
+                    bufs, chunks, opener, io_timeout, inflight, retry,
+                    merger, metrics, trace,
                 );
 
-                if let Err(err) = t.run().await {
-                    error!("tag={} task failed err={}", tag, err);
+                let result = match replay {
+                    Some(replay) => t.replay(replay).await,
+                    None => t.run().await,
+                };
+
+                if let Err(err) = result {
+                    error!(
+                        queue_id = queue_id, tag = tag;
+                        "task failed err={}", err
+                    );
                 }
             });
         }
 
+        if let Some(threshold) = self.config.hung_io_warning() {
+            let slots = self.slots.clone();
+            let inflight = self.inflight.clone();
+
+            self.runtime.spawn_daemon(
+                self.slots.len() as u16,
+                |submitter| async move {
+                    watchdog(submitter, slots, inflight, threshold).await
+                },
+            );
+        }
+
+        if let Some(merger) = &self.merger {
+            let merger = merger.clone();
+            let io_timeout = self.io_timeout.clone();
+            let metrics = self.metrics.clone();
+
+            // Polled after the tasks as it has a higher id.
+            self.runtime.spawn_daemon(
+                self.slots.len() as u16 + 1,
+                |submitter| {
+                    Batcher::new(merger, submitter, io_timeout, metrics)
+                },
+            );
+        }
+
+        if let Some(inbox) = self.inbox.take() {
+            let chunks = self.chunks.clone();
+
+            self.runtime.spawn_daemon(
+                self.slots.len() as u16 + 2,
+                |submitter| async move {
+                    serve_commands(submitter, inbox, chunks).await
+                },
+            );
+        }
+
         debug!("done spawning tasks");
 
         Ok(())
     }
 }
+
+/// Flushes the chunks at the given indexes and returns how many were
+/// synced.
+async fn flush_chunks(
+    submitter: &mut Submitter,
+    file_indexes: Vec<u32>,
+) -> usize {
+    let entries = file_indexes
+        .into_iter()
+        .map(|file_index| {
+            submitter.submit_entry(create_flush_sqe(file_index))
+        })
+        .collect::<Result<Vec<Waiter>>>();
+
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Failed to sync chunks err={}", err);
+            return 0;
+        }
+    };
+
+    let mut synced = 0;
+
+    for entry in entries {
+        let result = entry.await;
+
+        if result < 0 {
+            error!("Failed to sync a chunk err={}", result);
+        } else {
+            synced += 1;
+        }
+    }
+
+    synced
+}
+
+/// Carries out the commands sent to the control socket.
+async fn serve_commands(
+    mut submitter: Submitter,
+    mut inbox: WorkerInbox,
+    chunks: Rc<RefCell<ChunkCache>>,
+) {
+    while let Some((command, reply)) = inbox.next(&mut submitter).await {
+        let affected = match command {
+            WorkerCommand::Flush => {
+                let file_indexes = chunks.borrow().indexes();
+                flush_chunks(&mut submitter, file_indexes).await
+            }
+            WorkerCommand::CloseIdleChunks => {
+                // Chunks used while they were being flushed stay open
+                // until the next time.
+                let unflushed = chunks.borrow().unflushed_idle();
+                let file_indexes =
+                    unflushed.iter().map(|chunk| chunk.index).collect();
+                flush_chunks(&mut submitter, file_indexes).await;
+
+                let file_indexes = {
+                    let mut chunks = chunks.borrow_mut();
+                    unflushed
+                        .into_iter()
+                        .for_each(|chunk| chunks.flushed(chunk));
+                    chunks.close_idle()
+                };
+
+                for &file_index in &file_indexes {
+                    if let Err(err) =
+                        submitter.register_files_update(file_index, &[-1])
+                    {
+                        error!("Failed to close a chunk err={}", err);
+                    }
+                }
+
+                file_indexes.len()
+            }
+        };
+
+        _ = reply.send(affected);
+    }
+}
@@ -1,31 +1,484 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write as _};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering::Relaxed};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error, Result, bail};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// At most this many errors and warnings are logged from the same place
+/// per interval. The rest are counted and the count is reported with the
+/// next message logged from there.
+const BURST: u32 = 10;
+const INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => bail!("Unknown log level {}.", s),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+/// Where messages go. The journal gets the fields of a message as fields
+/// of the entry, syslog gets them as text, regardless of the format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Stdout,
+    Stderr,
+    Journald,
+    Syslog,
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "stderr" => Ok(Self::Stderr),
+            "journald" => Ok(Self::Journald),
+            "syslog" => Ok(Self::Syslog),
+            _ => bail!("Unknown log target {}.", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `[LEVEL]\tkey=value message`
+    Text,
+
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown log format {}.", s),
+        }
+    }
+}
+
+/// The fields of a message, e.g. `queue_id`.
+pub type Fields<'a> = &'a [(&'static str, &'a dyn fmt::Display)];
+
+/// The file and line a message is logged from.
+type Site = (&'static str, u32);
+
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+struct Logger {
+    format: Format,
+    target: Target,
+    /// The journal's or syslog's socket.
+    socket: Option<UnixDatagram>,
+    windows: BTreeMap<Site, Window>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    format: Format::Text,
+    target: Target::Stderr,
+    socket: None,
+    windows: BTreeMap::new(),
+});
+
+/// Development builds log everything by default.
+static LEVEL: AtomicU8 = AtomicU8::new(if cfg!(feature = "debug") {
+    Level::Debug as u8
+} else {
+    Level::Info as u8
+});
+
+/// Messages less severe than the level are dropped.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Relaxed) {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+#[inline(always)]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Relaxed)
+}
+
+pub fn set_format(format: Format) {
+    lock().format = format;
+}
+
+pub fn target() -> Target {
+    lock().target
+}
+
+pub fn set_target(target: Target) -> Result<()> {
+    let socket = match target {
+        Target::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(JOURNAL_SOCKET)
+                .context("Failed to connect to the journal.")?;
+            Some(socket)
+        }
+        Target::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(SYSLOG_SOCKET)
+                .context("Failed to connect to syslog.")?;
+            Some(socket)
+        }
+        _ => None,
+    };
+
+    let mut logger = lock();
+    logger.target = target;
+    logger.socket = socket;
+
+    Ok(())
+}
+
+/// Applies BLKCHNKR_LOG_LEVEL, BLKCHNKR_LOG_FORMAT and
+/// BLKCHNKR_LOG_TARGET.
+pub fn init_from_env() -> Result<()> {
+    if let Ok(level) = env::var("BLKCHNKR_LOG_LEVEL") {
+        set_level(level.parse().context("Invalid BLKCHNKR_LOG_LEVEL")?);
+    }
+
+    if let Ok(format) = env::var("BLKCHNKR_LOG_FORMAT") {
+        set_format(format.parse().context("Invalid BLKCHNKR_LOG_FORMAT")?);
+    }
+
+    if let Ok(target) = env::var("BLKCHNKR_LOG_TARGET") {
+        set_target(
+            target.parse().context("Invalid BLKCHNKR_LOG_TARGET")?,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Used by the macros, the level has been checked already.
+pub fn write(
+    level: Level,
+    site: Site,
+    fields: Fields,
+    args: fmt::Arguments,
+) {
+    let mut logger = lock();
+
+    let mut message = args.to_string();
+
+    if level <= Level::Warn {
+        let Some(suppressed) = logger.limit(site) else {
+            return;
+        };
+
+        if suppressed > 0 {
+            _ = write!(
+                message,
+                " ({} similar messages suppressed)",
+                suppressed
+            );
+        }
+    }
+
+    logger.emit(level, site, fields, &message);
+}
+
+fn lock() -> MutexGuard<'static, Logger> {
+    // Logging must keep working even if a thread panicked while logging.
+    LOGGER.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl Logger {
+    /// Returns None if the message is to be dropped, otherwise the number
+    /// of messages dropped since the last one logged.
+    fn limit(&mut self, site: Site) -> Option<u64> {
+        let now = Instant::now();
+        let window = self.windows.entry(site).or_insert(Window {
+            started: now,
+            logged: 0,
+            suppressed: 0,
+        });
+
+        if now.duration_since(window.started) >= INTERVAL {
+            window.started = now;
+            window.logged = 0;
+        }
+
+        if window.logged >= BURST {
+            window.suppressed += 1;
+            return None;
+        }
+
+        window.logged += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    fn emit(
+        &self,
+        level: Level,
+        site: Site,
+        fields: Fields,
+        message: &str,
+    ) {
+        if let Some(socket) = &self.socket {
+            let entry = match self.target {
+                Target::Syslog => syslog_line(level, fields, message),
+                _ => journal_entry(level, site, fields, message),
+            };
+            if socket.send(&entry).is_ok() {
+                return;
+            }
+        }
+
+        let line = match self.format {
+            Format::Text => text_line(level, fields, message),
+            Format::Json => json_line(level, fields, message),
+        };
+
+        _ = match self.target {
+            Target::Stdout => {
+                io::stdout().lock().write_all(line.as_bytes())
+            }
+            _ => io::stderr().lock().write_all(line.as_bytes()),
+        };
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+    }
+}
+
+fn text_line(level: Level, fields: Fields, message: &str) -> String {
+    let mut line = format!("[{}]\t", level_name(level));
+
+    for (key, value) in fields {
+        _ = write!(line, "{}={} ", key, value);
+    }
+
+    _ = writeln!(line, "{}", message);
+    line
+}
+
+fn json_line(level: Level, fields: Fields, message: &str) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut line = format!(
+        "{{\"time\":{}.{:03},\"level\":\"{}\"",
+        time.as_secs(),
+        time.subsec_millis(),
+        level
+    );
+
+    for (key, value) in fields {
+        let value = value.to_string();
+
+        // Numbers stay numbers, everything else is a string.
+        if value.parse::<i64>().is_ok() {
+            _ = write!(line, ",\"{}\":{}", key, value);
+        } else {
+            _ = write!(line, ",\"{}\":", key);
+            push_json_string(&mut line, &value);
+        }
+    }
+
+    line.push_str(",\"message\":");
+    push_json_string(&mut line, message);
+    line.push_str("}\n");
+    line
+}
+
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+/// The syslog severity of the level.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+    }
+}
+
+/// A message of the daemon facility as per RFC 3164, the syslog daemon
+/// adds the time and host.
+fn syslog_line(level: Level, fields: Fields, message: &str) -> Vec<u8> {
+    let mut line = format!(
+        "<{}>blkchnkr[{}]: ",
+        3 * 8 + priority(level),
+        process::id()
+    );
+
+    for (key, value) in fields {
+        _ = write!(line, "{}={} ", key, value);
+    }
+
+    line.push_str(message);
+    line.into_bytes()
+}
+
+/// An entry in the journal's native protocol, see systemd.journal-fields.
+fn journal_entry(
+    level: Level,
+    site: Site,
+    fields: Fields,
+    message: &str,
+) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(256);
+    push_journal_field(
+        &mut entry,
+        "PRIORITY",
+        &priority(level).to_string(),
+    );
+    push_journal_field(&mut entry, "SYSLOG_IDENTIFIER", "blkchnkr");
+    push_journal_field(&mut entry, "CODE_FILE", site.0);
+    push_journal_field(&mut entry, "CODE_LINE", &site.1.to_string());
+
+    for (key, value) in fields {
+        push_journal_field(
+            &mut entry,
+            &key.to_ascii_uppercase(),
+            &value.to_string(),
+        );
+    }
+
+    push_journal_field(&mut entry, "MESSAGE", message);
+    entry
+}
+
+fn push_journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+
+    // Values with newlines are prefixed with their length instead.
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
 macro_rules! __log {
-    ($level:tt,$($arg:tt)+) => {
-        println!("[{}]\t{}", $level, format!($($arg)*))
+    ($level:ident, $($key:ident = $value:expr),* ; $($arg:tt)+) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            $crate::log::write(
+                $crate::log::Level::$level,
+                (file!(), line!()),
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),*],
+                format_args!($($arg)+),
+            )
+        }
     };
 }
 
+/// Takes optional fields before the message, e.g.
+/// `warn!(queue_id = 1, tag = 2; "the message {}", arg)`.
 macro_rules! warn {
+    ($($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        __log!(Warn, $($key = $value),+ ; $($arg)+)
+    };
     ($($arg:tt)+) => {
-        __log!("WARN", $($arg)*)
+        __log!(Warn, ; $($arg)+)
     };
 }
 
 macro_rules! info {
+    ($($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        __log!(Info, $($key = $value),+ ; $($arg)+)
+    };
     ($($arg:tt)+) => {
-        __log!("INFO", $($arg)*)
+        __log!(Info, ; $($arg)+)
     };
 }
 
 macro_rules! error {
+    ($($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        __log!(Error, $($key = $value),+ ; $($arg)+)
+    };
     ($($arg:tt)+) => {
-        __log!("ERROR", $($arg)*)
+        __log!(Error, ; $($arg)+)
     };
 }
 
 macro_rules! debug {
     ($($arg:tt)+) => {
-        #[cfg(feature = "debug")]
-        eprintln!("[DEBUG]\t{}:{} tid={:?} {}", file!(), line!(), std::thread::current().id(),
-            format!($($arg)*))
+        __log!(Debug, ; "{}:{} tid={:?} {}", file!(), line!(),
+            std::thread::current().id(), format!($($arg)+))
     };
 }
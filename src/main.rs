@@ -7,29 +7,55 @@ use crate::cli::Command;
 #[macro_use]
 mod log;
 
+mod affinity;
+mod allocation;
 #[allow(unused, non_camel_case_types)]
 mod bindings;
 mod bindings_ext;
+mod chunk_cache;
+mod chunk_opener;
+mod chunk_registry;
 mod cli;
 mod commands;
 mod config;
+mod control;
+mod ctrl;
+mod daemon;
+mod heatmap;
+mod histogram;
 mod io_buffers;
 mod io_descriptor_map;
 mod io_worker;
+mod merge;
+mod metrics;
+mod notify;
 mod parts;
 mod queue_limits;
+mod replay;
+mod retry;
 mod runtime;
 mod sqes;
 mod task;
+mod trace;
 mod types;
 mod util;
+mod watchdog;
 
 fn main() -> Result<()> {
+    log::init_from_env()?;
+
     match cli::parse_cli(env::args())? {
         Command::Version(_) => commands::version::run(),
         Command::Help(_) => commands::help::run(),
         Command::Init(init) => commands::init::run(init),
         Command::Start(start) => commands::start::run(start),
+        Command::Quiesce(quiesce) => commands::quiesce::run(quiesce),
+        Command::Resume(resume) => commands::resume::run(resume),
+        Command::Stats(stats) => commands::stats::run(stats),
+        Command::Control(control) => commands::control::run(control),
         Command::Expand(expand) => commands::expand::run(expand),
+        Command::TraceDump(dump) => commands::trace::dump(dump),
+        Command::Replay(replay) => commands::replay::run(replay),
+        Command::Heatmap(heatmap) => commands::heatmap::run(heatmap),
     }
 }
@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use anyhow::Result;
+use io_uring::opcode::{Readv, Writev};
+use io_uring::types::{Fixed, Timespec};
+use nix::libc;
+
+use crate::bindings::UBLK_IO_OP_READ;
+use crate::metrics::WorkerMetrics;
+use crate::runtime::{Deferred, Submitter, Waiter};
+
+/// The most segments merged into one operation.
+const MAX_SEGMENTS: usize = 64;
+
+/// A read or write of a part of a request.
+pub struct Segment {
+    pub op: u32,
+    pub file_index: u32,
+    pub offset: u64,
+    pub buf: *mut u8,
+    pub len: u32,
+    pub rw_flags: i32,
+}
+
+struct Queued {
+    segment: Segment,
+    done: Deferred,
+}
+
+#[derive(Default)]
+struct State {
+    queued: Vec<Queued>,
+
+    /// The waker of the batcher.
+    waker: Option<Waker>,
+}
+
+/// Collects the reads and writes the tasks of a worker submit while they
+/// run. Once they're done, the batcher merges segments which are next to
+/// each other in the same chunk into vectored operations.
+#[derive(Default)]
+pub struct Merger {
+    state: RefCell<State>,
+}
+
+impl Merger {
+    pub fn submit(
+        &self,
+        submitter: &mut Submitter,
+        segment: Segment,
+    ) -> Waiter {
+        let (waiter, done) = submitter.defer();
+        let mut state = self.state.borrow_mut();
+
+        state.queued.push(Queued { segment, done });
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+
+        waiter
+    }
+}
+
+/// A vectored operation and the segments it's made of.
+struct Merged {
+    waiter: Waiter,
+
+    // Read by the kernel when the operation is submitted.
+    _iovecs: Box<[libc::iovec]>,
+
+    segments: Vec<(u32, Deferred)>,
+}
+
+impl Merged {
+    /// Hands each segment its share of the result. Segments past a short
+    /// result are reported as interrupted so that they're submitted again.
+    fn complete(self, result: i32) {
+        let mut left = result;
+
+        for (len, done) in self.segments {
+            if result < 0 {
+                done.complete(result);
+            } else if left > 0 {
+                done.complete(left.min(len as i32));
+                left -= len as i32;
+            } else {
+                done.complete(-libc::EINTR);
+            }
+        }
+    }
+}
+
+/// Runs as a daemon task. The runtime polls it after the tasks which woke
+/// it up so it sees all of the segments submitted in a round at once.
+pub struct Batcher {
+    merger: Rc<Merger>,
+    submitter: Submitter,
+    io_timeout: Option<Rc<Timespec>>,
+    metrics: Arc<WorkerMetrics>,
+
+    inflight: Vec<Merged>,
+}
+
+impl Batcher {
+    pub fn new(
+        merger: Rc<Merger>,
+        submitter: Submitter,
+        io_timeout: Option<Rc<Timespec>>,
+        metrics: Arc<WorkerMetrics>,
+    ) -> Self {
+        Self {
+            merger,
+            submitter,
+            io_timeout,
+            metrics,
+            inflight: Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut queued = {
+            let mut state = self.merger.state.borrow_mut();
+            std::mem::take(&mut state.queued)
+        };
+
+        if queued.is_empty() {
+            return;
+        }
+
+        queued.sort_unstable_by_key(|q| {
+            let s = &q.segment;
+            (s.file_index, s.op, s.rw_flags, s.offset)
+        });
+
+        let mut operations = 0;
+        let nr_segments = queued.len() as u64;
+        let mut queued = queued.into_iter().peekable();
+
+        while let Some(first) = queued.next() {
+            let mut group = vec![first];
+
+            while let Some(next) = queued.peek() {
+                let last = &group.last().unwrap().segment;
+                let next = &next.segment;
+
+                let adjacent = next.file_index == last.file_index
+                    && next.op == last.op
+                    && next.rw_flags == last.rw_flags
+                    && next.offset == last.offset + last.len as u64;
+
+                if !adjacent || group.len() == MAX_SEGMENTS {
+                    break;
+                }
+
+                group.push(queued.next().unwrap());
+            }
+
+            if let Err(err) = self.submit(group) {
+                error!("Failed to submit merged operation err={}", err);
+            }
+            operations += 1;
+        }
+
+        self.metrics.merged(nr_segments, operations);
+    }
+
+    fn submit(&mut self, group: Vec<Queued>) -> Result<()> {
+        let first = &group[0].segment;
+        let (op, file_index, offset, rw_flags) =
+            (first.op, first.file_index, first.offset, first.rw_flags);
+
+        let iovecs: Box<[libc::iovec]> = group
+            .iter()
+            .map(|q| libc::iovec {
+                iov_base: q.segment.buf.cast(),
+                iov_len: q.segment.len as usize,
+            })
+            .collect();
+
+        let fd = Fixed(file_index);
+        let nr = iovecs.len() as u32;
+
+        let sqe = if op == UBLK_IO_OP_READ {
+            Readv::new(fd, iovecs.as_ptr(), nr).offset(offset).build()
+        } else {
+            Writev::new(fd, iovecs.as_ptr(), nr)
+                .offset(offset)
+                .rw_flags(rw_flags)
+                .build()
+        };
+
+        let segments: Vec<_> =
+            group.into_iter().map(|q| (q.segment.len, q.done)).collect();
+
+        let result = self
+            .submitter
+            .submit_polled_entry(sqe, self.io_timeout.as_deref());
+
+        let waiter = match result {
+            Ok(waiter) => waiter,
+            Err(err) => {
+                for (_, done) in segments {
+                    done.complete(-libc::EIO);
+                }
+                return Err(err);
+            }
+        };
+
+        self.inflight.push(Merged {
+            waiter,
+            _iovecs: iovecs,
+            segments,
+        });
+
+        Ok(())
+    }
+}
+
+impl Future for Batcher {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.merger.state.borrow_mut().waker = Some(cx.waker().clone());
+        self.flush();
+
+        let mut i = 0;
+        while i < self.inflight.len() {
+            match Pin::new(&mut self.inflight[i].waiter).poll(cx) {
+                Poll::Ready(result) => {
+                    self.inflight.swap_remove(i).complete(result)
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        Poll::Pending
+    }
+}
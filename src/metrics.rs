@@ -0,0 +1,750 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use nix::errno::Errno;
+
+use crate::bindings::{
+    UBLK_IO_OP_FLUSH, UBLK_IO_OP_READ, UBLK_IO_OP_WRITE,
+    UBLK_IO_OP_WRITE_ZEROES,
+};
+use crate::config::Config;
+use crate::heatmap::{self, ChunkAccess};
+use crate::histogram::{Histogram, Snapshot};
+use crate::util::remove_stale_socket;
+
+/// Errnos above are counted as the last one.
+const MAX_ERRNO: usize = 4095;
+
+/// The ops whose latency is tracked, in the order of
+/// `WorkerMetrics::latency`.
+const OPS: [(u32, &str); 4] = [
+    (UBLK_IO_OP_READ, "read"),
+    (UBLK_IO_OP_WRITE, "write"),
+    (UBLK_IO_OP_FLUSH, "flush"),
+    (UBLK_IO_OP_WRITE_ZEROES, "write_zeroes"),
+];
+
+const QUANTILES: [f64; 3] = [0.5, 0.99, 0.999];
+
+/// Where the metrics are served, either a Unix socket or a TCP port on
+/// localhost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsListen {
+    Unix(PathBuf),
+    Tcp(u16),
+}
+
+impl FromStr for MetricsListen {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            return Ok(MetricsListen::Tcp(s.parse()?));
+        }
+
+        Ok(MetricsListen::Unix(PathBuf::from(s)))
+    }
+}
+
+impl fmt::Display for MetricsListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsListen::Unix(path) => write!(f, "{}", path.display()),
+            MetricsListen::Tcp(port) => write!(f, "{}", port),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QueueMetrics {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    flushes: AtomicU64,
+    write_zeroes: AtomicU64,
+
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+
+    short_retries: AtomicU64,
+    inflight: AtomicU64,
+}
+
+/// The latency of an op from the request's arrival to its commit, split
+/// into the time spent waiting for the reads, writes and flushes of chunks
+/// and the rest (including opening chunks).
+#[derive(Default)]
+struct Latency {
+    total: Histogram,
+    backing: Histogram,
+    overhead: Histogram,
+}
+
+type LatencyKind = (&'static str, fn(&Latency) -> &Histogram);
+
+const LATENCY_KINDS: [LatencyKind; 3] = [
+    ("total", |l| &l.total),
+    ("backing", |l| &l.backing),
+    ("overhead", |l| &l.overhead),
+];
+
+/// The accesses of a chunk. Unlike the other counters, they're shared by
+/// all workers as there's one for every chunk of the device.
+#[derive(Default)]
+struct ChunkCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+
+    /// In seconds since the Unix epoch.
+    last_access: AtomicU64,
+}
+
+/// The counters of one worker. Only the worker updates them, readers
+/// merge the counters of all workers.
+pub struct WorkerMetrics {
+    queues: Box<[QueueMetrics]>,
+    errors: Box<[AtomicU64]>,
+
+    /// Operations on the backing store tried again, by errno.
+    retries: Box<[AtomicU64]>,
+
+    latency: [Latency; OPS.len()],
+
+    chunks_opened: AtomicU64,
+    chunks_created: AtomicU64,
+
+    /// Reads and writes merged into vectored operations and the number of
+    /// operations they were merged into.
+    merged_segments: AtomicU64,
+    merged_operations: AtomicU64,
+
+    /// Indexed by the chunk's number.
+    chunks: Arc<[ChunkCounters]>,
+}
+
+impl WorkerMetrics {
+    fn new(nr_queues: u16, chunks: Arc<[ChunkCounters]>) -> Self {
+        Self {
+            queues: (0..nr_queues).map(|_| Default::default()).collect(),
+            errors: (0..=MAX_ERRNO).map(|_| AtomicU64::new(0)).collect(),
+            retries: (0..=MAX_ERRNO).map(|_| AtomicU64::new(0)).collect(),
+            latency: Default::default(),
+            chunks_opened: AtomicU64::new(0),
+            chunks_created: AtomicU64::new(0),
+            merged_segments: AtomicU64::new(0),
+            merged_operations: AtomicU64::new(0),
+            chunks,
+        }
+    }
+
+    pub fn start_request(&self, queue_id: u16, op: u32, bytes: u64) {
+        let queue = &self.queues[queue_id as usize];
+
+        let (count, total) = match op {
+            UBLK_IO_OP_READ => (&queue.reads, Some(&queue.read_bytes)),
+            UBLK_IO_OP_WRITE => {
+                (&queue.writes, Some(&queue.written_bytes))
+            }
+            UBLK_IO_OP_FLUSH => (&queue.flushes, None),
+            UBLK_IO_OP_WRITE_ZEROES => (&queue.write_zeroes, None),
+            _ => return,
+        };
+
+        count.fetch_add(1, Relaxed);
+        if let Some(total) = total {
+            total.fetch_add(bytes, Relaxed);
+        }
+
+        queue.inflight.fetch_add(1, Relaxed);
+    }
+
+    /// Records the result committed for a request.
+    pub fn finish_request(&self, queue_id: u16, result: i32) {
+        let queue = &self.queues[queue_id as usize];
+        queue.inflight.fetch_sub(1, Relaxed);
+
+        if result < 0 {
+            let errno = (-result as usize).min(MAX_ERRNO);
+            self.errors[errno].fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn record_latency(
+        &self,
+        op: u32,
+        total: Duration,
+        backing: Duration,
+    ) {
+        let Some(idx) = OPS.iter().position(|(o, _)| *o == op) else {
+            return;
+        };
+
+        let latency = &self.latency[idx];
+        latency.total.record(total);
+        latency.backing.record(backing);
+        latency.overhead.record(total.saturating_sub(backing));
+    }
+
+    /// Counts an operation which failed with the result and is about to
+    /// be tried again.
+    pub fn retry(&self, result: i32) {
+        let errno = (-result as usize).min(MAX_ERRNO);
+        self.retries[errno].fetch_add(1, Relaxed);
+    }
+
+    pub fn log_retries(&self) {
+        for (errno, count) in self.retries.iter().enumerate() {
+            let count = count.load(Relaxed);
+
+            if count > 0 {
+                info!(
+                    "Retried {} operations which failed with {:?}",
+                    count,
+                    Errno::from_raw(errno as i32)
+                );
+            }
+        }
+    }
+
+    pub fn short_retry(&self, queue_id: u16) {
+        self.queues[queue_id as usize]
+            .short_retries
+            .fetch_add(1, Relaxed);
+    }
+
+    pub fn chunk_opened(&self, created: bool) {
+        self.chunks_opened.fetch_add(1, Relaxed);
+        if created {
+            self.chunks_created.fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn merged(&self, segments: u64, operations: u64) {
+        self.merged_segments.fetch_add(segments, Relaxed);
+        self.merged_operations.fetch_add(operations, Relaxed);
+    }
+
+    pub fn log_merge_ratio(&self) {
+        let segments = self.merged_segments.load(Relaxed);
+        let operations = self.merged_operations.load(Relaxed);
+
+        if operations == 0 {
+            return;
+        }
+
+        info!(
+            "Merged {} reads and writes into {} operations ({:.2} per \
+            operation)",
+            segments,
+            operations,
+            segments as f64 / operations as f64
+        );
+    }
+
+    /// Counts a read or write (including write zeroes) of a chunk.
+    #[inline(always)]
+    pub fn chunk_access(&self, file_num: u32, op: u32) {
+        let Some(chunk) = self.chunks.get(file_num as usize) else {
+            return;
+        };
+
+        let count = if op == UBLK_IO_OP_READ {
+            &chunk.reads
+        } else {
+            &chunk.writes
+        };
+        count.fetch_add(1, Relaxed);
+
+        // Other workers might be accessing the chunk too, only write the
+        // time when it has changed.
+        let now = heatmap::now();
+        if chunk.last_access.load(Relaxed) != now {
+            chunk.last_access.store(now, Relaxed);
+        }
+    }
+}
+
+/// The metrics of all workers, including the ones which are gone (e.g.
+/// after the device has been quiesced) such that counters never go down.
+pub struct Metrics {
+    nr_queues: u16,
+    chunks: Arc<[ChunkCounters]>,
+    workers: Mutex<Vec<Arc<WorkerMetrics>>>,
+}
+
+impl Metrics {
+    pub fn new(config: &Config, nr_queues: u16) -> Self {
+        let nr_chunks = config.size.div_ceil(config.chunk_size);
+
+        Self {
+            nr_queues,
+            chunks: (0..nr_chunks).map(|_| Default::default()).collect(),
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the counters of a new worker.
+    pub fn register_worker(&self) -> Arc<WorkerMetrics> {
+        let worker = Arc::new(WorkerMetrics::new(
+            self.nr_queues,
+            self.chunks.clone(),
+        ));
+        self.lock().push(worker.clone());
+        worker
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let workers = self.lock();
+        let mut out = String::with_capacity(4096);
+
+        let queue_sum =
+            |queue_id: usize, get: fn(&QueueMetrics) -> u64| {
+                workers
+                    .iter()
+                    .map(|w| get(&w.queues[queue_id]))
+                    .sum::<u64>()
+            };
+
+        type Counter = (&'static str, fn(&QueueMetrics) -> u64);
+
+        let requests: [Counter; 4] = [
+            ("read", |q| q.reads.load(Relaxed)),
+            ("write", |q| q.writes.load(Relaxed)),
+            ("flush", |q| q.flushes.load(Relaxed)),
+            ("write_zeroes", |q| q.write_zeroes.load(Relaxed)),
+        ];
+        let bytes: [Counter; 2] = [
+            ("read", |q| q.read_bytes.load(Relaxed)),
+            ("write", |q| q.written_bytes.load(Relaxed)),
+        ];
+
+        header(&mut out, "requests_total", "counter", "Requests handled.");
+        for queue_id in 0..self.nr_queues as usize {
+            for (op, get) in requests {
+                sample(
+                    &mut out,
+                    "requests_total",
+                    &format!("queue=\"{}\",op=\"{}\"", queue_id, op),
+                    queue_sum(queue_id, get),
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bytes_total",
+            "counter",
+            "Bytes read or written.",
+        );
+        for queue_id in 0..self.nr_queues as usize {
+            for (op, get) in bytes {
+                sample(
+                    &mut out,
+                    "bytes_total",
+                    &format!("queue=\"{}\",op=\"{}\"", queue_id, op),
+                    queue_sum(queue_id, get),
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "short_io_retries_total",
+            "counter",
+            "Short reads and writes submitted again.",
+        );
+        for queue_id in 0..self.nr_queues as usize {
+            sample(
+                &mut out,
+                "short_io_retries_total",
+                &format!("queue=\"{}\"", queue_id),
+                queue_sum(queue_id, |q| q.short_retries.load(Relaxed)),
+            );
+        }
+
+        header(
+            &mut out,
+            "inflight_requests",
+            "gauge",
+            "Requests being handled.",
+        );
+        for queue_id in 0..self.nr_queues as usize {
+            sample(
+                &mut out,
+                "inflight_requests",
+                &format!("queue=\"{}\"", queue_id),
+                queue_sum(queue_id, |q| q.inflight.load(Relaxed)),
+            );
+        }
+
+        header(
+            &mut out,
+            "errors_total",
+            "counter",
+            "Requests failed, by errno.",
+        );
+        for (errno, count) in errno_counts(&workers, |w| &w.errors) {
+            sample(
+                &mut out,
+                "errors_total",
+                &format!("errno=\"{:?}\"", errno),
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "retries_total",
+            "counter",
+            "Operations on the backing store tried again, by errno.",
+        );
+        for (errno, count) in errno_counts(&workers, |w| &w.retries) {
+            sample(
+                &mut out,
+                "retries_total",
+                &format!("errno=\"{:?}\"", errno),
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "chunks_opened_total",
+            "counter",
+            "Chunks opened.",
+        );
+        sample(
+            &mut out,
+            "chunks_opened_total",
+            "",
+            workers.iter().map(|w| w.chunks_opened.load(Relaxed)).sum(),
+        );
+
+        header(
+            &mut out,
+            "chunks_created_total",
+            "counter",
+            "Chunks created.",
+        );
+        sample(
+            &mut out,
+            "chunks_created_total",
+            "",
+            workers.iter().map(|w| w.chunks_created.load(Relaxed)).sum(),
+        );
+
+        header(
+            &mut out,
+            "merged_segments_total",
+            "counter",
+            "Reads and writes merged into vectored operations.",
+        );
+        sample(
+            &mut out,
+            "merged_segments_total",
+            "",
+            workers
+                .iter()
+                .map(|w| w.merged_segments.load(Relaxed))
+                .sum(),
+        );
+
+        header(
+            &mut out,
+            "merged_operations_total",
+            "counter",
+            "Vectored operations the reads and writes were merged into.",
+        );
+        sample(
+            &mut out,
+            "merged_operations_total",
+            "",
+            workers
+                .iter()
+                .map(|w| w.merged_operations.load(Relaxed))
+                .sum(),
+        );
+
+        header(
+            &mut out,
+            "latency_seconds",
+            "summary",
+            "Time from a request's arrival to its commit.",
+        );
+        for (op_idx, (_, op)) in OPS.iter().enumerate() {
+            for (kind, get) in LATENCY_KINDS {
+                let snapshot = merge_latency(&workers, op_idx, get);
+                let labels = format!("op=\"{}\",kind=\"{}\"", op, kind);
+
+                for quantile in QUANTILES {
+                    _ = writeln!(
+                        out,
+                        "blkchnkr_latency_seconds{{{},quantile=\"{}\"}} {}",
+                        labels,
+                        quantile,
+                        snapshot.quantile(quantile).as_secs_f64()
+                    );
+                }
+                _ = writeln!(
+                    out,
+                    "blkchnkr_latency_seconds_count{{{}}} {}",
+                    labels,
+                    snapshot.count()
+                );
+            }
+        }
+
+        out
+    }
+
+    /// Renders a summary for humans.
+    pub fn render_stats(&self) -> String {
+        let workers = self.lock();
+        let mut out = String::with_capacity(2048);
+
+        _ = writeln!(
+            out,
+            "{:<22}{:>12}{:>12}{:>12}{:>12}",
+            "latency (us)", "count", "p50", "p99", "p999"
+        );
+
+        for (op_idx, (_, op)) in OPS.iter().enumerate() {
+            for (kind, get) in LATENCY_KINDS {
+                let snapshot = merge_latency(&workers, op_idx, get);
+                let name = format!("{} {}", op, kind);
+
+                _ = write!(out, "{:<22}{:>12}", name, snapshot.count());
+                for quantile in QUANTILES {
+                    let micros = snapshot.quantile(quantile).as_nanos()
+                        as f64
+                        / 1000.0;
+                    _ = write!(out, "{:>12.1}", micros);
+                }
+                _ = writeln!(out);
+            }
+        }
+
+        let errors: u64 = workers
+            .iter()
+            .flat_map(|w| w.errors.iter())
+            .map(|count| count.load(Relaxed))
+            .sum();
+        let opened: u64 =
+            workers.iter().map(|w| w.chunks_opened.load(Relaxed)).sum();
+        let created: u64 =
+            workers.iter().map(|w| w.chunks_created.load(Relaxed)).sum();
+
+        _ = writeln!(out);
+        _ = writeln!(out, "errors: {}", errors);
+
+        let retries = errno_counts(&workers, |w| &w.retries);
+        _ = write!(
+            out,
+            "retries: {}",
+            retries.iter().map(|(_, count)| count).sum::<u64>()
+        );
+        for (i, (errno, count)) in retries.iter().enumerate() {
+            let sep = if i == 0 { " (" } else { ", " };
+            _ = write!(out, "{}{:?} {}", sep, errno, count);
+        }
+        if !retries.is_empty() {
+            _ = write!(out, ")");
+        }
+        _ = writeln!(out);
+        _ = writeln!(
+            out,
+            "chunks opened: {} (created {})",
+            opened, created
+        );
+
+        let segments: u64 = workers
+            .iter()
+            .map(|w| w.merged_segments.load(Relaxed))
+            .sum();
+        let operations: u64 = workers
+            .iter()
+            .map(|w| w.merged_operations.load(Relaxed))
+            .sum();
+
+        if operations > 0 {
+            _ = writeln!(
+                out,
+                "merged: {} reads and writes into {} operations ({:.2} \
+                per operation)",
+                segments,
+                operations,
+                segments as f64 / operations as f64
+            );
+        }
+
+        out
+    }
+
+    /// The accesses of all chunks which have been accessed.
+    pub fn chunk_accesses(&self) -> BTreeMap<u32, ChunkAccess> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .map(|(file_num, chunk)| {
+                let access = ChunkAccess {
+                    reads: chunk.reads.load(Relaxed),
+                    writes: chunk.writes.load(Relaxed),
+                    last_access: chunk.last_access.load(Relaxed),
+                };
+
+                (file_num as u32, access)
+            })
+            .filter(|(_, access)| access.total() > 0)
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Arc<WorkerMetrics>>> {
+        self.workers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The counts of all workers by errno, leaving out the ones which are zero.
+fn errno_counts(
+    workers: &[Arc<WorkerMetrics>],
+    get: fn(&WorkerMetrics) -> &[AtomicU64],
+) -> Vec<(Errno, u64)> {
+    (1..=MAX_ERRNO)
+        .map(|errno| {
+            let count =
+                workers.iter().map(|w| get(w)[errno].load(Relaxed)).sum();
+
+            (Errno::from_raw(errno as i32), count)
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+fn merge_latency(
+    workers: &[Arc<WorkerMetrics>],
+    op_idx: usize,
+    get: fn(&Latency) -> &Histogram,
+) -> Snapshot {
+    let mut snapshot = Snapshot::default();
+
+    for worker in workers {
+        get(&worker.latency[op_idx]).merge_into(&mut snapshot);
+    }
+
+    snapshot
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP blkchnkr_{} {}", name, help);
+    _ = writeln!(out, "# TYPE blkchnkr_{} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    if labels.is_empty() {
+        _ = writeln!(out, "blkchnkr_{} {}", name, value);
+    } else {
+        _ = writeln!(out, "blkchnkr_{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Serves the metrics over HTTP on a background thread. Relative socket
+/// paths are relative to the repository.
+pub fn serve(config: &Config, metrics: Arc<Metrics>) -> Result<()> {
+    let Some(listen) = config.metrics() else {
+        return Ok(());
+    };
+
+    match listen {
+        MetricsListen::Unix(path) => {
+            let path = config.repository.join(path);
+
+            remove_stale_socket(&path);
+
+            let listener =
+                UnixListener::bind(&path).with_context(|| {
+                    format!(
+                        "Failed to listen for metrics on {}",
+                        path.display()
+                    )
+                })?;
+
+            info!("Serving metrics on {}", path.display());
+            spawn_server(move || {
+                for conn in listener.incoming().flatten() {
+                    _ = conn
+                        .set_read_timeout(Some(Duration::from_secs(1)));
+                    handle_connection(conn, &metrics);
+                }
+            })
+        }
+        MetricsListen::Tcp(port) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, *port))
+                .with_context(|| {
+                    format!("Failed to listen for metrics on {}", port)
+                })?;
+
+            info!("Serving metrics on 127.0.0.1:{}", port);
+            spawn_server(move || {
+                for conn in listener.incoming().flatten() {
+                    _ = conn
+                        .set_read_timeout(Some(Duration::from_secs(1)));
+                    handle_connection(conn, &metrics);
+                }
+            })
+        }
+    }
+}
+
+fn spawn_server(f: impl FnOnce() + Send + 'static) -> Result<()> {
+    thread::Builder::new()
+        .name("metrics".into())
+        .spawn(f)
+        .context("Failed to start the metrics server.")?;
+
+    Ok(())
+}
+
+/// Answers any request with the metrics.
+fn handle_connection(mut conn: impl Read + Write, metrics: &Metrics) {
+    if read_request(&mut conn).is_err() {
+        return;
+    }
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.0 200 OK\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+
+    _ = conn.write_all(response.as_bytes());
+}
+
+fn read_request(conn: &mut impl Read) -> io::Result<()> {
+    let mut request = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+
+    // The request itself doesn't matter, wait for the end of the headers.
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(())
+}
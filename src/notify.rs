@@ -0,0 +1,84 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The socket of the service manager, if the server runs as a systemd
+/// service with `Type=notify`. See sd_notify(3).
+static SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+fn socket() -> Option<&'static UnixDatagram> {
+    SOCKET.get_or_init(connect).as_ref()
+}
+
+fn connect() -> Option<UnixDatagram> {
+    let path = env::var_os("NOTIFY_SOCKET")?;
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // Abstract sockets start with @.
+        match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => socket
+                .connect_addr(&SocketAddr::from_abstract_name(name)?)?,
+            None => socket.connect(&path)?,
+        }
+
+        Ok(socket)
+    });
+
+    match result {
+        Ok(socket) => Some(socket),
+        Err(err) => {
+            warn!(
+                "Unable to connect to the service manager at {}. Err: {}",
+                path.display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+fn notify(state: &str) {
+    if let Some(socket) = socket()
+        && let Err(err) = socket.send(state.as_bytes())
+    {
+        warn!("Unable to notify the service manager. Err: {}", err);
+    }
+}
+
+/// The device is live.
+pub fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Stopping");
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// How often the watchdog has to be pinged, half of what the service
+/// manager expects. None if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(process::id())
+    {
+        return None;
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
@@ -20,6 +20,13 @@ pub struct QueueLimits {
 
     pub write_cache: bool,
     pub fua: bool,
+
+    pub rotational: bool,
+
+    pub max_segments: u16,
+    pub max_segment_size: u32,
+
+    pub io_poll: bool,
 }
 
 impl Default for QueueLimits {
@@ -35,6 +42,13 @@ impl Default for QueueLimits {
 
             write_cache: true,
             fua: false,
+
+            rotational: false,
+
+            max_segments: u16::MAX,
+            max_segment_size: u32::MAX,
+
+            io_poll: false,
         }
     }
 }
@@ -78,9 +92,13 @@ pub fn limits_from_device(config_path: &PathBuf) -> Result<QueueLimits> {
     read_int_limit!(limits, dir, minimum_io_size);
     read_int_limit!(limits, dir, optimal_io_size);
     read_int_limit!(limits, dir, dma_alignment);
+    read_int_limit!(limits, dir, max_segments);
+    read_int_limit!(limits, dir, max_segment_size);
 
     match_limit!(limits, dir, write_cache, "write back");
     match_limit!(limits, dir, fua, "1");
+    match_limit!(limits, dir, rotational, "1");
+    match_limit!(limits, dir, io_poll, "1");
 
     Ok(limits)
 }
@@ -0,0 +1,94 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use io_uring::opcode::Timeout;
+use io_uring::types::Timespec;
+
+use crate::bindings::{ublksrv_io_desc, ublksrv_io_desc__bindgen_ty_1};
+use crate::runtime::Submitter;
+use crate::trace::Record;
+
+/// Hands out the records of a trace to the tasks of a worker, one request
+/// per task at a time.
+pub struct Replay {
+    records: RefCell<VecDeque<Record>>,
+
+    /// Whether to keep the original time between requests or to submit
+    /// them as fast as possible.
+    timing: bool,
+
+    /// The time of the first record, the trace might not start at zero.
+    /// The records are expected to be sorted by time.
+    offset: Duration,
+    started: Instant,
+
+    replayed: Cell<u64>,
+    failed: Cell<u64>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>, timing: bool) -> Self {
+        let offset = records.first().map_or(Duration::ZERO, |r| r.time);
+
+        Self {
+            records: RefCell::new(records.into()),
+            timing,
+            offset,
+            started: Instant::now(),
+            replayed: Cell::new(0),
+            failed: Cell::new(0),
+        }
+    }
+
+    /// Returns the next request, once it's due. None once all records
+    /// have been handed out.
+    pub async fn next(
+        &self,
+        submitter: &mut Submitter,
+    ) -> Result<Option<ublksrv_io_desc>> {
+        let Some(record) = self.records.borrow_mut().pop_front() else {
+            return Ok(None);
+        };
+
+        if self.timing {
+            let due =
+                self.started + record.time.saturating_sub(self.offset);
+            let delay = due.saturating_duration_since(Instant::now());
+
+            if !delay.is_zero() {
+                let timeout = Timespec::from(delay);
+                submitter
+                    .submit_entry(Timeout::new(&timeout).build())?
+                    .await;
+            }
+        }
+
+        Ok(Some(ublksrv_io_desc {
+            op_flags: record.op_flags,
+            __bindgen_anon_1: ublksrv_io_desc__bindgen_ty_1 {
+                nr_sectors: record.nr_sectors,
+            },
+            start_sector: record.start_sector,
+            addr: 0,
+        }))
+    }
+
+    /// Records the result of a replayed request.
+    pub fn finish(&self, result: i32) {
+        self.replayed.set(self.replayed.get() + 1);
+
+        if result < 0 {
+            self.failed.set(self.failed.get() + 1);
+        }
+    }
+
+    pub fn replayed(&self) -> u64 {
+        self.replayed.get()
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.get()
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+use std::{fmt, str::FromStr};
+
+use anyhow::{Error, Result, bail};
+use io_uring::opcode::Timeout;
+use io_uring::types::Timespec;
+use nix::errno::Errno;
+
+use crate::config::Config;
+use crate::runtime::Submitter;
+
+/// The longest the backoff between two attempts grows to.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A list of errnos in the "EAGAIN,EIO,28" format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrnoList(Vec<Errno>);
+
+impl FromStr for ErrnoList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut errnos = Vec::new();
+
+        for name in s.trim().split(',') {
+            let name = name.trim();
+
+            let errno = match name.parse::<i32>() {
+                Ok(num) => Errno::from_raw(num),
+                Err(_) => (1..4096)
+                    .map(Errno::from_raw)
+                    .find(|errno| format!("{:?}", errno) == name)
+                    .unwrap_or(Errno::UnknownErrno),
+            };
+
+            if errno == Errno::UnknownErrno {
+                bail!("Unknown errno \"{}\"", name);
+            }
+
+            errnos.push(errno);
+        }
+
+        Ok(Self(errnos))
+    }
+}
+
+impl fmt::Display for ErrnoList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, errno) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{:?}", errno)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decides which failed operations on the backing store are retried and
+/// how long to wait in between.
+pub struct RetryPolicy {
+    errnos: Vec<Errno>,
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            errnos: config
+                .retry_errnos()
+                .map_or(Vec::new(), |l| l.0.clone()),
+            max_attempts: config.retry_attempts(),
+            backoff: config.retry_backoff(),
+        }
+    }
+
+    /// Returns whether an operation which failed with the result should be
+    /// tried again given it has been retried `attempt` times already.
+    pub fn should_retry(&self, result: i32, attempt: u32) -> bool {
+        attempt < self.max_attempts
+            && self.errnos.contains(&Errno::from_raw(-result))
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Waits before the given attempt. The backoff doubles with every
+    /// attempt.
+    pub async fn backoff(
+        &self,
+        submitter: &mut Submitter,
+        attempt: u32,
+    ) -> Result<()> {
+        let delay = self
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_BACKOFF);
+
+        if delay.is_zero() {
+            return Ok(());
+        }
+
+        let timeout = Timespec::from(delay);
+        submitter
+            .submit_entry(Timeout::new(&timeout).build())?
+            .await;
+
+        Ok(())
+    }
+}
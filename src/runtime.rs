@@ -1,83 +1,309 @@
 use anyhow::{Result, bail};
-use io_uring::squeue;
+use io_uring::opcode::LinkTimeout;
+use io_uring::squeue::{self, Flags};
+use io_uring::types::Timespec;
+use nix::libc;
 use smallvec::SmallVec;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
 use std::future::Future;
 use std::io::ErrorKind::Interrupted;
 use std::os::fd::RawFd;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::Poll::{self, Pending, Ready};
-use std::task::{Context, Waker};
+use std::task::{Context, RawWaker, RawWakerVTable, Waker};
+use std::time::Instant;
 
 use crate::types::Ring;
 
+/// The user data of entries nobody waits for, such as linked timeouts.
+const IGNORED: u64 = u64::MAX;
+
+thread_local! {
+    /// Ids of tasks woken up since they were last polled. Every thread
+    /// runs at most one runtime so the queue is per thread.
+    static READY: RefCell<Vec<u16>> = RefCell::new(Vec::with_capacity(256));
+}
+
+/// The waker of a task is just the task's id and waking it up means
+/// putting the id into the ready queue. No allocations or reference
+/// counting involved.
+fn task_waker(task_id: u16) -> Waker {
+    const VTABLE: RawWakerVTable =
+        RawWakerVTable::new(clone, wake, wake, drop);
+
+    fn raw_waker(task_id: usize) -> RawWaker {
+        RawWaker::new(task_id as *const (), &VTABLE)
+    }
+
+    fn clone(data: *const ()) -> RawWaker {
+        raw_waker(data as usize)
+    }
+
+    fn wake(data: *const ()) {
+        READY.with_borrow_mut(|ready| ready.push(data as usize as u16));
+    }
+
+    fn drop(_: *const ()) {}
+
+    unsafe { Waker::from_raw(raw_waker(task_id as usize)) }
+}
+
+enum Slot {
+    Free,
+    /// Submitted and waiting for the completion.
+    Pending(Option<Waker>),
+    /// Completed but not yet picked up by the waiter.
+    Done(i32),
+    /// The waiter is gone, the slot is freed once the entry completes.
+    Abandoned,
+}
+
+/// A table of in-flight entries. The user data of every submitted entry is
+/// the index of its slot so delivering a completion is a plain array
+/// access.
+struct Completions {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+
+    /// When completions were last delivered.
+    reaped: Instant,
+}
+
+impl Completions {
+    fn with_capacity(capacity: usize) -> Self {
+        let mut slots = Vec::with_capacity(capacity);
+        slots.resize_with(capacity, || Slot::Free);
+
+        Self {
+            slots,
+            free: (0..capacity as u32).rev().collect(),
+            reaped: Instant::now(),
+        }
+    }
+
+    fn alloc(&mut self) -> u32 {
+        let idx = match self.free.pop() {
+            Some(idx) => idx,
+            None => {
+                self.slots.push(Slot::Free);
+                (self.slots.len() - 1) as u32
+            }
+        };
+
+        self.slots[idx as usize] = Slot::Pending(None);
+        idx
+    }
+
+    fn release(&mut self, idx: u32) {
+        self.slots[idx as usize] = Slot::Free;
+        self.free.push(idx);
+    }
+
+    fn complete(&mut self, idx: u32, result: i32) {
+        let Some(slot) = self.slots.get_mut(idx as usize) else {
+            return;
+        };
+
+        match slot {
+            Slot::Pending(waker) => {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+                *slot = Slot::Done(result);
+            }
+            Slot::Abandoned => self.release(idx),
+            Slot::Free | Slot::Done(_) => {
+                debug_assert!(false, "Unexpected completion idx={}", idx);
+            }
+        }
+    }
+
+    fn poll(&mut self, idx: u32, waker: &Waker) -> Option<i32> {
+        let slot = &mut self.slots[idx as usize];
+
+        match slot {
+            Slot::Done(result) => {
+                let result = *result;
+                self.release(idx);
+                Some(result)
+            }
+            Slot::Pending(current) => {
+                if !current.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                    *current = Some(waker.clone());
+                }
+                None
+            }
+            Slot::Free | Slot::Abandoned => {
+                unreachable!("Polled a slot that's not in use")
+            }
+        }
+    }
+
+    fn abandon(&mut self, idx: u32) {
+        match self.slots[idx as usize] {
+            Slot::Done(_) => self.release(idx),
+            Slot::Pending(_) => self.slots[idx as usize] = Slot::Abandoned,
+            Slot::Free | Slot::Abandoned => {}
+        }
+    }
+}
+
+/// A ring set up with IORING_SETUP_IOPOLL. Such a ring only takes reads
+/// and writes of files opened with O_DIRECT and its completions have to be
+/// actively reaped.
+struct PollRing {
+    ring: RefCell<Ring>,
+    inflight: Cell<usize>,
+}
+
+struct Spawned {
+    fut: Pin<Box<dyn Future<Output = ()>>>,
+
+    /// The runtime doesn't wait for daemon tasks to finish.
+    daemon: bool,
+}
+
 pub struct Runtime {
     ring: Rc<RefCell<Ring>>,
-    mailbox: Rc<RefCell<HashMap<u64, i32>>>,
-    tasks: HashMap<u8, Pin<Box<dyn Future<Output = ()>>>>,
+    poll_ring: Option<Rc<PollRing>>,
+    completions: Rc<RefCell<Completions>>,
+    tasks: Vec<Option<Spawned>>,
+
+    /// The number of tasks which aren't daemons.
+    nr_tasks: usize,
 }
 
 impl Runtime {
-    pub fn new(ring: Ring) -> Self {
-        let capacity = ring.params().sq_entries() as usize;
+    /// Entries submitted with `Submitter::submit_polled_entry` go to the
+    /// poll ring if there's one.
+    pub fn new(ring: Ring, poll_ring: Option<Ring>) -> Self {
+        let capacity = ring.params().cq_entries() as usize
+            + poll_ring
+                .as_ref()
+                .map_or(0, |ring| ring.params().cq_entries() as usize);
 
         Self {
             ring: Rc::new(RefCell::new(ring)),
-            mailbox: Rc::new(RefCell::new(HashMap::new())),
-            tasks: HashMap::with_capacity(capacity),
+            poll_ring: poll_ring.map(|ring| {
+                Rc::new(PollRing {
+                    ring: RefCell::new(ring),
+                    inflight: Cell::new(0),
+                })
+            }),
+            completions: Rc::new(RefCell::new(
+                Completions::with_capacity(capacity),
+            )),
+            tasks: Vec::new(),
+            nr_tasks: 0,
         }
     }
 
-    pub fn spawn<T, F>(&mut self, tag: u8, to_fut: T)
+    pub fn spawn<T, F>(&mut self, task_id: u16, to_fut: T)
     where
         T: FnOnce(Submitter) -> F,
         F: Future<Output = ()> + 'static,
     {
-        self.tasks.insert(
-            tag,
-            Box::pin(to_fut(Submitter::new(
-                self.ring.clone(),
-                self.mailbox.clone(),
-                tag,
-            ))),
-        );
+        self.spawn_task(task_id, to_fut, false);
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        self.run_all_tasks();
+    /// Spawns a task which runs for as long as there are other tasks.
+    pub fn spawn_daemon<T, F>(&mut self, task_id: u16, to_fut: T)
+    where
+        T: FnOnce(Submitter) -> F,
+        F: Future<Output = ()> + 'static,
+    {
+        self.spawn_task(task_id, to_fut, true);
+    }
+
+    fn spawn_task<T, F>(&mut self, task_id: u16, to_fut: T, daemon: bool)
+    where
+        T: FnOnce(Submitter) -> F,
+        F: Future<Output = ()> + 'static,
+    {
+        let task_id = task_id as usize;
+        if self.tasks.len() <= task_id {
+            self.tasks.resize_with(task_id + 1, || None);
+        }
+
+        let fut = Box::pin(to_fut(Submitter::new(
+            self.ring.clone(),
+            self.poll_ring.clone(),
+            self.completions.clone(),
+        )));
+
+        let prev = self.tasks[task_id].replace(Spawned { fut, daemon });
+        if prev.is_none_or(|prev| prev.daemon) && !daemon {
+            self.nr_tasks += 1;
+        }
 
+        READY.with_borrow_mut(|ready| ready.push(task_id as u16));
+    }
+
+    pub fn run(&mut self) -> Result<()> {
         loop {
-            if self.tasks.is_empty() {
+            self.run_ready_tasks();
+
+            if self.nr_tasks == 0 {
                 return Ok(());
             }
 
             self.submit_and_wait()?;
-            self.process_entries()?;
-            self.run_tasks();
+            self.process_entries();
         }
     }
 
-    /// Run all tasks to kick off their state machines.
-    fn run_all_tasks(&mut self) {
-        let mut cx = Context::from_waker(Waker::noop());
+    /// Run only tasks that have been woken up and should be able to make
+    /// progress.
+    fn run_ready_tasks(&mut self) {
+        loop {
+            let mut ready: SmallVec<[u16; 128]> =
+                READY.with_borrow_mut(|ready| ready.drain(..).collect());
+
+            if ready.is_empty() {
+                return;
+            }
+
+            // A task might have been woken up multiple times.
+            ready.sort_unstable();
+            ready.dedup();
+
+            for task_id in ready {
+                let Some(Some(task)) =
+                    self.tasks.get_mut(task_id as usize)
+                else {
+                    continue;
+                };
 
-        let tags: SmallVec<[u8; 128]> =
-            self.tasks.keys().cloned().collect();
+                let waker = task_waker(task_id);
+                let mut cx = Context::from_waker(&waker);
 
-        for tag in tags {
-            let task = self.tasks.get_mut(&tag).unwrap();
-            if task.as_mut().poll(&mut cx).is_ready() {
-                self.tasks.remove(&tag);
+                if task.fut.as_mut().poll(&mut cx).is_ready() {
+                    if !task.daemon {
+                        self.nr_tasks -= 1;
+                    }
+                    self.tasks[task_id as usize] = None;
+                }
             }
         }
     }
 
-    /// Wait for IO events.
+    /// Wait for IO events. With polled entries in flight, this doesn't
+    /// block but polls both rings instead.
     fn submit_and_wait(&mut self) -> Result<()> {
+        let mut want = 1;
+
+        if let Some(poll_ring) = &self.poll_ring {
+            // Submitting to an IOPOLL ring also reaps its completions.
+            poll_ring.ring.borrow().submit()?;
+
+            if poll_ring.inflight.get() > 0 {
+                want = 0;
+            }
+        }
+
         loop {
-            match self.ring.borrow().submit_and_wait(1) {
+            match self.ring.borrow().submit_and_wait(want) {
                 Ok(_) => return Ok(()),
                 Err(err) => {
                     if err.kind() == Interrupted {
@@ -93,105 +319,220 @@ impl Runtime {
         }
     }
 
-    /// Deliver the messages to tasks' mailboxes based on user data.
-    fn process_entries(&mut self) -> Result<()> {
-        loop {
-            let mut ring = self.ring.borrow_mut();
-            let Some(entry) = ring.completion().next() else {
-                return Ok(());
-            };
+    /// Deliver the results to their slots and wake up the waiting tasks.
+    fn process_entries(&mut self) {
+        let mut ring = self.ring.borrow_mut();
+        let mut completions = self.completions.borrow_mut();
+        completions.reaped = Instant::now();
 
-            self.mailbox
-                .borrow_mut()
-                .insert(entry.user_data(), entry.result());
+        for entry in ring.completion() {
+            if entry.user_data() != IGNORED {
+                completions
+                    .complete(entry.user_data() as u32, entry.result());
+            }
         }
-    }
 
-    /// Run only tasks that should be able to make progress.
-    fn run_tasks(&mut self) {
-        let mut cx = Context::from_waker(Waker::noop());
+        if let Some(poll_ring) = &self.poll_ring {
+            let mut ring = poll_ring.ring.borrow_mut();
 
-        let entry_keys: SmallVec<[u64; 128]> =
-            self.mailbox.borrow().keys().cloned().collect();
+            for entry in ring.completion() {
+                poll_ring.inflight.set(poll_ring.inflight.get() - 1);
+                completions
+                    .complete(entry.user_data() as u32, entry.result());
+            }
+        }
+    }
+}
 
-        for entry_key in entry_keys {
-            // Strip off the upper bits to get the tag without idx.
-            let tag = entry_key as u8;
+/// Pushes the entries such that they end up next to each other, which
+/// linked entries require.
+fn push_entries(ring: &mut Ring, entries: &[squeue::Entry]) -> Result<()> {
+    fn free(ring: &mut Ring) -> usize {
+        let sq = ring.submission();
+        sq.capacity() - sq.len()
+    }
 
-            if let Some(task) = self.tasks.get_mut(&tag)
-                && task.as_mut().poll(&mut cx).is_ready() {
-                    self.tasks.remove(&tag);
-                }
+    if free(ring) < entries.len() {
+        ring.submit()?;
+
+        // With SQPOLL, submitting only wakes up the kernel thread which
+        // might not have consumed the entries yet.
+        while free(ring) < entries.len() {
+            ring.submitter().squeue_wait()?;
         }
     }
+
+    unsafe { ring.submission().push_multiple(entries)? };
+    Ok(())
 }
 
 pub struct Submitter {
     ring: Rc<RefCell<Ring>>,
-    mailbox: Rc<RefCell<HashMap<u64, i32>>>,
-    tag: u8,
-    idx: u64,
+    poll_ring: Option<Rc<PollRing>>,
+    completions: Rc<RefCell<Completions>>,
 }
 
 impl Submitter {
     fn new(
         ring: Rc<RefCell<Ring>>,
-        mailbox: Rc<RefCell<HashMap<u64, i32>>>,
-        tag: u8,
+        poll_ring: Option<Rc<PollRing>>,
+        completions: Rc<RefCell<Completions>>,
     ) -> Self {
         Self {
             ring,
-            mailbox,
-            tag,
-            idx: 1,
+            poll_ring,
+            completions,
         }
     }
 
+    /// When the runtime last delivered completions. Right after a waiter
+    /// resolves, that's when its entry completed, unless the entry had
+    /// completed before the waiter was awaited.
+    pub fn reaped(&self) -> Instant {
+        self.completions.borrow().reaped
+    }
+
     pub fn submit_entry(
         &mut self,
+        entry: squeue::Entry,
+    ) -> Result<Waiter> {
+        self.submit_entry_to(&mut self.ring.borrow_mut(), entry, None)
+    }
+
+    /// Links a timeout to the entry. If it fires, the entry is canceled and
+    /// completes with -ECANCELED. The timeout is read when the entry is
+    /// submitted to the kernel so it has to stay valid until the entry
+    /// completes.
+    pub fn submit_entry_with_timeout(
+        &mut self,
+        entry: squeue::Entry,
+        timeout: Option<&Timespec>,
+    ) -> Result<Waiter> {
+        self.submit_entry_to(&mut self.ring.borrow_mut(), entry, timeout)
+    }
+
+    /// Submits a read or write to the poll ring if there's one, otherwise
+    /// the same as `submit_entry_with_timeout`. Timeouts cannot be linked
+    /// to entries of a poll ring so the timeout is ignored there.
+    pub fn submit_polled_entry(
+        &mut self,
+        entry: squeue::Entry,
+        timeout: Option<&Timespec>,
+    ) -> Result<Waiter> {
+        let Some(poll_ring) = &self.poll_ring else {
+            return self.submit_entry_with_timeout(entry, timeout);
+        };
+
+        let waiter = self.submit_entry_to(
+            &mut poll_ring.ring.borrow_mut(),
+            entry,
+            None,
+        )?;
+        poll_ring.inflight.set(poll_ring.inflight.get() + 1);
+
+        Ok(waiter)
+    }
+
+    fn submit_entry_to(
+        &self,
+        ring: &mut Ring,
         mut entry: squeue::Entry,
+        timeout: Option<&Timespec>,
     ) -> Result<Waiter> {
-        let entry_key = self.idx << 8 | (self.tag as u64);
-        entry.set_user_data(entry_key);
+        let idx = self.completions.borrow_mut().alloc();
+        entry.set_user_data(idx as u64);
 
-        let mut ring = self.ring.borrow_mut();
-        if ring.submission().is_full() {
-            ring.submit()?;
+        let result = match timeout {
+            Some(timeout) => {
+                let timeout =
+                    LinkTimeout::new(timeout).build().user_data(IGNORED);
+                push_entries(ring, &[entry.flags(Flags::IO_LINK), timeout])
+            }
+            None => push_entries(ring, &[entry]),
+        };
+
+        if let Err(err) = result {
+            self.completions.borrow_mut().release(idx);
+            return Err(err);
         }
-        unsafe { ring.submission().push(&entry)? };
 
-        self.idx = self.idx.wrapping_add(1);
-        Ok(Waiter::new(self.mailbox.clone(), entry_key))
+        Ok(Waiter::new(self.completions.clone(), idx))
     }
 
+    /// Updates the registered files of both rings.
     pub fn register_files_update(
         &mut self,
         idx: u32,
         fds: &[RawFd],
     ) -> Result<usize> {
+        if let Some(poll_ring) = &self.poll_ring {
+            poll_ring
+                .ring
+                .borrow()
+                .submitter()
+                .register_files_update(idx, fds)?;
+        }
+
         self.ring
             .borrow_mut()
             .submitter()
             .register_files_update(idx, fds)
             .map_err(Into::into)
     }
+
+    /// Returns a waiter which isn't backed by an entry of its own, e.g.
+    /// for entries combined with others. It completes once the result is
+    /// handed over to the `Deferred`.
+    pub fn defer(&mut self) -> (Waiter, Deferred) {
+        let idx = self.completions.borrow_mut().alloc();
+
+        let waiter = Waiter::new(self.completions.clone(), idx);
+        let deferred = Deferred {
+            completions: self.completions.clone(),
+            idx: Some(idx),
+        };
+
+        (waiter, deferred)
+    }
+}
+
+/// Completes a waiter returned by `Submitter::defer`. The waiter completes
+/// with -ECANCELED if this is dropped without a result.
+pub struct Deferred {
+    completions: Rc<RefCell<Completions>>,
+    idx: Option<u32>,
+}
+
+impl Deferred {
+    pub fn complete(mut self, result: i32) {
+        self.finish(result);
+    }
+
+    fn finish(&mut self, result: i32) {
+        if let Some(idx) = self.idx.take() {
+            self.completions.borrow_mut().complete(idx, result);
+        }
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        self.finish(-libc::ECANCELED);
+    }
 }
 
 pub struct Waiter {
-    mailbox: Rc<RefCell<HashMap<u64, i32>>>,
+    completions: Rc<RefCell<Completions>>,
 
-    entry_key: u64,
+    idx: u32,
     result: Option<i32>,
 }
 
 impl Waiter {
-    fn new(
-        mailbox: Rc<RefCell<HashMap<u64, i32>>>,
-        entry_key: u64,
-    ) -> Self {
+    fn new(completions: Rc<RefCell<Completions>>, idx: u32) -> Self {
         Self {
-            mailbox,
-            entry_key,
+            completions,
+            idx,
             result: None,
         }
     }
@@ -202,24 +543,29 @@ impl Future for Waiter {
 
     fn poll(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Self::Output> {
-        match self.result {
-            Some(result) => Ready(result),
-            None => {
-                let mail = {
-                    let mut mailbox = self.mailbox.borrow_mut();
-                    mailbox.remove(&self.entry_key)
-                };
+        if let Some(result) = self.result {
+            return Ready(result);
+        }
 
-                match mail {
-                    Some(result) => {
-                        self.as_mut().result = Some(result);
-                        Ready(result)
-                    }
-                    None => Pending,
-                }
+        let result =
+            self.completions.borrow_mut().poll(self.idx, cx.waker());
+
+        match result {
+            Some(result) => {
+                self.result = Some(result);
+                Ready(result)
             }
+            None => Pending,
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if self.result.is_none() {
+            self.completions.borrow_mut().abandon(self.idx);
         }
     }
 }
@@ -1,5 +1,4 @@
 use std::io;
-use std::mem::MaybeUninit;
 use std::slice;
 
 use anyhow::Result;
@@ -8,8 +7,10 @@ use io_uring::cqueue::Entry32;
 use io_uring::opcode::Fallocate;
 use io_uring::opcode::Fsync;
 use io_uring::opcode::Read;
+use io_uring::opcode::ReadFixed;
 use io_uring::opcode::UringCmd16;
 use io_uring::opcode::Write;
+use io_uring::opcode::WriteFixed;
 use io_uring::squeue::Entry;
 use io_uring::squeue::Entry128;
 use io_uring::types::SubmitArgs;
@@ -28,7 +29,10 @@ use crate::bindings::{
 use crate::bindings_ext::UBLK_U_CMD_ADD_DEV;
 use crate::bindings_ext::UBLK_U_CMD_DEL_DEV_ASYNC;
 use crate::bindings_ext::UBLK_U_CMD_END_USER_RECOVERY;
-use crate::bindings_ext::UBLK_U_CMD_GET_DEV_INFO;
+use crate::bindings_ext::UBLK_U_CMD_GET_DEV_INFO2;
+use crate::bindings_ext::UBLK_U_CMD_GET_PARAMS;
+use crate::bindings_ext::UBLK_U_CMD_GET_QUEUE_AFFINITY;
+use crate::bindings_ext::UBLK_U_CMD_QUIESCE_DEV;
 use crate::bindings_ext::UBLK_U_CMD_SET_PARAMS;
 use crate::bindings_ext::UBLK_U_CMD_START_DEV;
 use crate::bindings_ext::UBLK_U_CMD_START_USER_RECOVERY;
@@ -39,6 +43,7 @@ use crate::parts::Part;
 use crate::task::Task;
 use crate::types::AddResult;
 use crate::types::Ring128;
+use crate::util::ublkc_path;
 
 fn serialize<T, const N: usize>(cmd: T) -> [u8; N] {
     let size = size_of::<T>();
@@ -59,11 +64,11 @@ fn create_io_cmd_sqe(fd: u32, op: u32, cmd: ublksrv_io_cmd) -> Entry {
 
 pub fn create_fetch_req_sqe(fd: u32, task: &Task) -> Entry {
     let mut cmd = ublksrv_io_cmd {
-        tag: task.tag as u16,
-        q_id: task.queue_id as u16,
+        tag: task.tag,
+        q_id: task.queue_id,
         ..Default::default()
     };
-    cmd.__bindgen_anon_1.addr = task.bufs.borrow().get_buf_addr(task.tag);
+    cmd.__bindgen_anon_1.addr = task.bufs.borrow().get_buf_addr(task.slot);
 
     create_io_cmd_sqe(fd, UBLK_U_IO_FETCH_REQ, cmd)
 }
@@ -74,16 +79,79 @@ pub fn create_fetch_req_commit_sqe(
     result: i32,
 ) -> Entry {
     let mut cmd = ublksrv_io_cmd {
-        tag: task.tag as u16,
-        q_id: task.queue_id as u16,
+        tag: task.tag,
+        q_id: task.queue_id,
         result,
         ..Default::default()
     };
-    cmd.__bindgen_anon_1.addr = task.bufs.borrow().get_buf_addr(task.tag);
+    cmd.__bindgen_anon_1.addr = task.bufs.borrow().get_buf_addr(task.slot);
 
     create_io_cmd_sqe(fd, UBLK_U_IO_COMMIT_AND_FETCH_REQ, cmd)
 }
 
+/// The buffer of a control command. Commands sent to unprivileged devices
+/// (and GET_DEV_INFO2 in general) need to carry the path of the char
+/// device in front of the actual payload so that the driver can check the
+/// caller's permissions.
+struct CtrlBuf {
+    buf: Vec<u8>,
+    dev_path_len: u16,
+}
+
+impl CtrlBuf {
+    fn new(dev_id: u32, with_dev_path: bool, payload_len: usize) -> Self {
+        let mut buf = Vec::new();
+
+        if with_dev_path {
+            buf.extend_from_slice(ublkc_path(dev_id).as_bytes());
+        }
+
+        let dev_path_len = buf.len() as u16;
+        buf.resize(buf.len() + payload_len, 0);
+
+        Self { buf, dev_path_len }
+    }
+
+    fn with_payload<T>(
+        dev_id: u32,
+        with_dev_path: bool,
+        payload: &T,
+    ) -> Self {
+        let mut buf = Self::new(dev_id, with_dev_path, size_of::<T>());
+
+        let ptr = payload as *const T;
+        let slice =
+            unsafe { slice::from_raw_parts(ptr.cast(), size_of::<T>()) };
+        buf.buf[buf.dev_path_len as usize..].copy_from_slice(slice);
+
+        buf
+    }
+
+    fn apply(&mut self, cmd: &mut ublksrv_ctrl_cmd) {
+        cmd.addr = self.buf.as_mut_ptr().addr() as _;
+        cmd.len = self.buf.len() as _;
+        cmd.dev_path_len = self.dev_path_len;
+    }
+
+    fn payload_bytes(&self) -> &[u8] {
+        &self.buf[self.dev_path_len as usize..]
+    }
+
+    fn payload<T: Copy>(&self) -> T {
+        debug_assert!(
+            self.buf.len() - self.dev_path_len as usize >= size_of::<T>()
+        );
+
+        unsafe {
+            self.buf
+                .as_ptr()
+                .add(self.dev_path_len as usize)
+                .cast::<T>()
+                .read_unaligned()
+        }
+    }
+}
+
 fn create_ctrl_cmd_sqe(
     fd: Fixed,
     op: u32,
@@ -163,19 +231,21 @@ pub fn send_get_info_cmd(
     ring: &mut Ring128,
     fd: Fixed,
 ) -> Result<ublksrv_ctrl_dev_info> {
-    let mut dev_info = MaybeUninit::<ublksrv_ctrl_dev_info>::uninit();
+    // GET_DEV_INFO2 works for both privileged and unprivileged devices
+    // but always requires the path to the char device.
+    let mut buf =
+        CtrlBuf::new(dev_id, true, ublksrv_ctrl_dev_info::len() as _);
 
-    let cmd = ublksrv_ctrl_cmd {
+    let mut cmd = ublksrv_ctrl_cmd {
         dev_id,
         queue_id: u16::MAX,
-        addr: dev_info.as_mut_ptr().addr() as _,
-        len: ublksrv_ctrl_dev_info::len(),
         ..Default::default()
     };
-    let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_GET_DEV_INFO, cmd);
+    buf.apply(&mut cmd);
+    let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_GET_DEV_INFO2, cmd);
 
     match submit_and_wait(ring, sqe)? {
-        0 => Ok(unsafe { dev_info.assume_init() }),
+        0 => Ok(buf.payload()),
         res => bail!(
             "Got an error while trying to get info about the device. Err: {}",
             io::Error::from_raw_os_error(-res)
@@ -183,16 +253,54 @@ pub fn send_get_info_cmd(
     }
 }
 
+/// Returns the CPU mask (one bit per CPU) of the given hardware queue.
+pub fn send_get_queue_affinity_cmd(
+    dev_info: &ublksrv_ctrl_dev_info,
+    queue_id: u16,
+    mask_len: usize,
+    ring: &mut Ring128,
+    fd: Fixed,
+) -> Result<Vec<u8>> {
+    let mut buf = CtrlBuf::new(
+        dev_info.dev_id,
+        dev_info.is_unprivileged(),
+        mask_len,
+    );
+
+    // The kernel reads the queue from data[0], queue_id is ignored.
+    let mut cmd = ublksrv_ctrl_cmd {
+        dev_id: dev_info.dev_id,
+        queue_id,
+        data: [queue_id as u64],
+        ..Default::default()
+    };
+    buf.apply(&mut cmd);
+    let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_GET_QUEUE_AFFINITY, cmd);
+
+    match submit_and_wait(ring, sqe)? {
+        0 => Ok(buf.payload_bytes().to_vec()),
+        res => bail!(
+            "Got an error while trying to get the affinity of queue {}. Err: {}",
+            queue_id,
+            io::Error::from_raw_os_error(-res)
+        ),
+    }
+}
+
 pub fn send_start_recovery_cmd(
     dev_info: ublksrv_ctrl_dev_info,
     ring: &mut Ring128,
     fd: Fixed,
 ) -> Result<()> {
-    let cmd = ublksrv_ctrl_cmd {
+    let mut buf =
+        CtrlBuf::new(dev_info.dev_id, dev_info.is_unprivileged(), 0);
+
+    let mut cmd = ublksrv_ctrl_cmd {
         dev_id: dev_info.dev_id,
         queue_id: u16::MAX,
         ..Default::default()
     };
+    buf.apply(&mut cmd);
     let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_START_USER_RECOVERY, cmd);
 
     match submit_and_wait(ring, sqe)? {
@@ -210,13 +318,18 @@ pub fn send_set_params_cmd(
     ring: &mut Ring128,
     fd: Fixed,
 ) -> Result<()> {
-    let cmd = ublksrv_ctrl_cmd {
+    let mut buf = CtrlBuf::with_payload(
+        dev_info.dev_id,
+        dev_info.is_unprivileged(),
+        &params,
+    );
+
+    let mut cmd = ublksrv_ctrl_cmd {
         dev_id: dev_info.dev_id,
         queue_id: u16::MAX,
-        len: ublk_params::len(),
-        addr: &raw const params as u64,
         ..Default::default()
     };
+    buf.apply(&mut cmd);
     let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_SET_PARAMS, cmd);
 
     match submit_and_wait(ring, sqe)? {
@@ -227,6 +340,38 @@ pub fn send_set_params_cmd(
     }
 }
 
+pub fn send_get_params_cmd(
+    dev_info: &ublksrv_ctrl_dev_info,
+    ring: &mut Ring128,
+    fd: Fixed,
+) -> Result<ublk_params> {
+    let params = ublk_params {
+        len: ublk_params::len() as _,
+        ..Default::default()
+    };
+    let mut buf = CtrlBuf::with_payload(
+        dev_info.dev_id,
+        dev_info.is_unprivileged(),
+        &params,
+    );
+
+    let mut cmd = ublksrv_ctrl_cmd {
+        dev_id: dev_info.dev_id,
+        queue_id: u16::MAX,
+        ..Default::default()
+    };
+    buf.apply(&mut cmd);
+    let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_GET_PARAMS, cmd);
+
+    match submit_and_wait(ring, sqe)? {
+        0 => Ok(buf.payload()),
+        res => bail!(
+            "Got an error while trying to get the device's parameters. Err: {}",
+            io::Error::from_raw_os_error(-res)
+        ),
+    }
+}
+
 pub fn send_start_recover_dev_cmd(
     is_new_device: bool,
     dev_info: &ublksrv_ctrl_dev_info,
@@ -234,12 +379,16 @@ pub fn send_start_recover_dev_cmd(
     pid: u32,
     fd: Fixed,
 ) -> Result<()> {
+    let mut buf =
+        CtrlBuf::new(dev_info.dev_id, dev_info.is_unprivileged(), 0);
+
     let mut cmd = ublksrv_ctrl_cmd {
         dev_id: dev_info.dev_id,
         queue_id: u16::MAX,
         ..Default::default()
     };
     cmd.data[0] = pid as u64;
+    buf.apply(&mut cmd);
 
     let cmd_op = if is_new_device {
         UBLK_U_CMD_START_DEV
@@ -261,11 +410,15 @@ pub fn send_stop_dev_cmd(
     ring: &mut Ring128,
     fd: Fixed,
 ) -> Result<()> {
-    let cmd = ublksrv_ctrl_cmd {
+    let mut buf =
+        CtrlBuf::new(dev_info.dev_id, dev_info.is_unprivileged(), 0);
+
+    let mut cmd = ublksrv_ctrl_cmd {
         dev_id: dev_info.dev_id,
         queue_id: u16::MAX,
         ..Default::default()
     };
+    buf.apply(&mut cmd);
     let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_STOP_DEV, cmd);
 
     match submit_and_wait(ring, sqe)? {
@@ -276,16 +429,50 @@ pub fn send_stop_dev_cmd(
     }
 }
 
+/// Waits (at most timeout_ms) for in-flight IO to complete and aborts all
+/// outstanding fetch requests. New IO is held by the driver until the
+/// device is recovered.
+pub fn send_quiesce_dev_cmd(
+    dev_info: &ublksrv_ctrl_dev_info,
+    timeout_ms: u64,
+    ring: &mut Ring128,
+    fd: Fixed,
+) -> Result<()> {
+    let mut buf =
+        CtrlBuf::new(dev_info.dev_id, dev_info.is_unprivileged(), 0);
+
+    let mut cmd = ublksrv_ctrl_cmd {
+        dev_id: dev_info.dev_id,
+        queue_id: u16::MAX,
+        ..Default::default()
+    };
+    cmd.data[0] = timeout_ms;
+    buf.apply(&mut cmd);
+    let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_QUIESCE_DEV, cmd);
+
+    match submit_and_wait(ring, sqe)? {
+        0 => Ok(()),
+        res => bail!(
+            "Got an error while trying to quiesce the device. Err: {}",
+            io::Error::from_raw_os_error(-res)
+        ),
+    }
+}
+
 pub fn send_del_dev_cmd(
     dev_info: &ublksrv_ctrl_dev_info,
     ring: &mut Ring128,
     fd: Fixed,
 ) -> Result<()> {
-    let cmd = ublksrv_ctrl_cmd {
+    let mut buf =
+        CtrlBuf::new(dev_info.dev_id, dev_info.is_unprivileged(), 0);
+
+    let mut cmd = ublksrv_ctrl_cmd {
         dev_id: dev_info.dev_id,
         queue_id: u16::MAX,
         ..Default::default()
     };
+    buf.apply(&mut cmd);
     let sqe = create_ctrl_cmd_sqe(fd, UBLK_U_CMD_DEL_DEV_ASYNC, cmd);
 
     match submit_and_wait(ring, sqe)? {
@@ -314,33 +501,32 @@ pub fn create_rw_sqe_with_offset(
     desc: &ublksrv_io_desc,
     curr_offset: u32,
 ) -> Entry {
-    let buf = task.bufs.borrow_mut().get_buf_with_offsets(
-        task.tag,
-        part.buf_offset,
-        curr_offset,
-    );
-
-    if op == UBLK_IO_OP_READ {
-        Read::new(
-            Fixed(file_index),
-            buf,
-            (part.nr_sectors << 9) - curr_offset,
-        )
-        .offset((part.start_sector << 9) + curr_offset as u64)
-        .build()
-    } else {
-        Write::new(
-            Fixed(file_index),
-            buf,
-            (part.nr_sectors << 9) - curr_offset,
-        )
-        .offset((part.start_sector << 9) + curr_offset as u64)
-        .rw_flags(fua_flags(desc))
-        .build()
+    let mut bufs = task.bufs.borrow_mut();
+    let buf =
+        bufs.get_buf_with_offsets(task.slot, part.buf_offset, curr_offset);
+
+    let fd = Fixed(file_index);
+    let len = (part.nr_sectors << 9) - curr_offset;
+    let offset = (part.start_sector << 9) + curr_offset as u64;
+
+    // Registered buffers save the kernel pinning the pages on every request.
+    match (op == UBLK_IO_OP_READ, bufs.is_registered()) {
+        (true, true) => ReadFixed::new(fd, buf, len, task.slot)
+            .offset(offset)
+            .build(),
+        (true, false) => Read::new(fd, buf, len).offset(offset).build(),
+        (false, true) => WriteFixed::new(fd, buf, len, task.slot)
+            .offset(offset)
+            .rw_flags(fua_flags(desc))
+            .build(),
+        (false, false) => Write::new(fd, buf, len)
+            .offset(offset)
+            .rw_flags(fua_flags(desc))
+            .build(),
     }
 }
 
-fn fua_flags(desc: &ublksrv_io_desc) -> i32 {
+pub fn fua_flags(desc: &ublksrv_io_desc) -> i32 {
     if desc.op_flags & UBLK_IO_F_FUA != 0 {
         libc::RWF_DSYNC
     } else {
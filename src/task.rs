@@ -1,4 +1,9 @@
-use std::{cell::RefCell, collections::HashMap, os::fd::AsRawFd, rc::Rc};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
     bindings::{
@@ -6,52 +11,90 @@ use crate::{
         UBLK_IO_OP_WRITE_ZEROES, ublksrv_io_desc,
     },
     bindings_ext::UBLK_IO_RES_ABORT,
+    chunk_cache::{ChunkCache, ChunkRef},
+    chunk_opener::ChunkOpener,
     config::Config,
     io_buffers::IoBuffers,
     io_descriptor_map::IoDescriptorMap,
     io_worker::UBLKC_FD_IDX,
+    merge::{Merger, Segment},
+    metrics::WorkerMetrics,
     parts::{Part, parts_for_event},
+    replay::Replay,
+    retry::RetryPolicy,
     runtime::{Submitter, Waiter},
     sqes::{
         create_fetch_req_commit_sqe, create_fetch_req_sqe,
         create_flush_sqe, create_rw_sqe, create_rw_sqe_with_offset,
-        create_write_zeroes_sqe,
+        create_write_zeroes_sqe, fua_flags,
     },
-    util::open_or_create_chunk,
+    trace::TraceBuffer,
+    watchdog::InFlight,
 };
 
 use anyhow::{Context, Result, bail};
-use nix::libc;
+use io_uring::types::Timespec;
+use nix::{errno::Errno, libc};
 use smallvec::SmallVec;
 
 pub struct Task {
     pub submitter: Submitter,
-    pub queue_id: usize,
+    pub queue_id: u16,
     pub config: Config,
-    pub tag: u8,
+    pub tag: u16,
+    pub slot: u16,
     pub descs: Rc<RefCell<IoDescriptorMap>>,
     pub bufs: Rc<RefCell<IoBuffers>>,
-    pub file_indexes: Rc<RefCell<HashMap<u32, u32>>>,
+    pub chunks: Rc<RefCell<ChunkCache>>,
+    pub opener: Rc<ChunkOpener>,
+    pub io_timeout: Option<Rc<Timespec>>,
+    pub inflight: Rc<RefCell<InFlight>>,
+    pub retry: Rc<RetryPolicy>,
+    pub merger: Option<Rc<Merger>>,
+    pub metrics: Arc<WorkerMetrics>,
+    pub trace: Option<Rc<TraceBuffer>>,
+
+    /// The time the current request has spent waiting for the backing
+    /// store.
+    backing: Duration,
 }
 
 impl Task {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         submitter: Submitter,
-        queue_id: usize,
+        queue_id: u16,
         config: Config,
-        tag: u8,
+        tag: u16,
+        slot: u16,
         descs: Rc<RefCell<IoDescriptorMap>>,
         bufs: Rc<RefCell<IoBuffers>>,
-        file_indexes: Rc<RefCell<HashMap<u32, u32>>>,
+        chunks: Rc<RefCell<ChunkCache>>,
+        opener: Rc<ChunkOpener>,
+        io_timeout: Option<Rc<Timespec>>,
+        inflight: Rc<RefCell<InFlight>>,
+        retry: Rc<RetryPolicy>,
+        merger: Option<Rc<Merger>>,
+        metrics: Arc<WorkerMetrics>,
+        trace: Option<Rc<TraceBuffer>>,
     ) -> Self {
         Self {
             submitter,
             queue_id,
             config,
             tag,
+            slot,
             descs,
             bufs,
-            file_indexes,
+            chunks,
+            opener,
+            io_timeout,
+            inflight,
+            retry,
+            merger,
+            metrics,
+            trace,
+            backing: Duration::ZERO,
         }
     }
 
@@ -76,7 +119,10 @@ impl Task {
                 self.queue_id, self.tag
             );
 
-            let result = match self.process_request().await {
+            // The fetch has just completed.
+            let fetched = self.submitter.reaped();
+
+            let result = match self.process_request(fetched).await {
                 Ok(res) => {
                     debug!(
                         "queue_id={} tag={} committing result {}",
@@ -86,8 +132,8 @@ impl Task {
                 }
                 Err(err) => {
                     error!(
-                        "queue_id={} tag={} failed to handle request err={}",
-                        self.queue_id, self.tag, err
+                        queue_id = self.queue_id, tag = self.tag;
+                        "failed to handle request err={}", err
                     );
                     self.send_commit_fetch_req(-libc::EIO).await?
                 }
@@ -103,25 +149,92 @@ impl Task {
 
             if result < 0 {
                 error!(
-                    "queue_id={} tag={} received an error during commit: {}",
-                    self.queue_id, self.tag, result
+                    queue_id = self.queue_id, tag = self.tag;
+                    "received an error during commit: {}", result
                 );
             }
         }
     }
 
+    /// Handles the requests of a trace instead of the device's until
+    /// there are none left.
+    pub async fn replay(&mut self, replay: Rc<Replay>) -> Result<()> {
+        while let Some(desc) = replay.next(&mut self.submitter).await? {
+            self.descs.borrow_mut().set(self.tag as usize, desc);
+
+            let result = match self.process_request(Instant::now()).await {
+                Ok(res) => res,
+                Err(err) => {
+                    error!(
+                        queue_id = self.queue_id, tag = self.tag;
+                        "failed to handle request err={}", err
+                    );
+                    -libc::EIO
+                }
+            };
+
+            replay.finish(result);
+        }
+
+        Ok(())
+    }
+
     async fn send_fetch_req(&mut self) -> Result<i32> {
         let sqe = create_fetch_req_sqe(UBLKC_FD_IDX, self);
         Ok(self.submitter.submit_entry(sqe)?.await)
     }
 
-    async fn process_request(&mut self) -> Result<i32> {
+    /// `fetched` is when the request arrived, its latency is measured from
+    /// then.
+    async fn process_request(&mut self, fetched: Instant) -> Result<i32> {
         debug!(
             "queue_id={} tag={} processing request",
             self.queue_id, self.tag
         );
 
         let desc = self.descs.borrow()[self.tag as usize];
+
+        let bytes =
+            unsafe { desc.__bindgen_anon_1.nr_sectors as u64 } << 9;
+        self.metrics.start_request(self.queue_id, desc.op(), bytes);
+
+        self.inflight.borrow_mut().start(self.slot, &desc);
+        self.backing = Duration::ZERO;
+        let result = self.handle_request(desc).await;
+        let backing = self.backing;
+
+        let committed = *result.as_ref().unwrap_or(&-libc::EIO);
+        self.metrics.finish_request(self.queue_id, committed);
+
+        if let Some(elapsed) = self.inflight.borrow_mut().finish(self.slot)
+        {
+            info!(
+                queue_id = self.queue_id, tag = self.tag;
+                "hung request finished after {}s", elapsed.as_secs()
+            );
+        }
+
+        // The result is committed right after this returns.
+        self.metrics
+            .record_latency(desc.op(), fetched.elapsed(), backing);
+
+        if let Some(trace) = &self.trace {
+            trace.record(
+                self.queue_id,
+                self.tag,
+                &desc,
+                fetched,
+                committed,
+            );
+        }
+
+        result
+    }
+
+    async fn handle_request(
+        &mut self,
+        desc: ublksrv_io_desc,
+    ) -> Result<i32> {
         match desc.op() {
             UBLK_IO_OP_READ | UBLK_IO_OP_WRITE => {
                 self.process_rw_request(desc.op(), desc).await
@@ -150,17 +263,43 @@ impl Task {
 
         // // Start off the entries in parallel. The runtime doesn't wait for the
         // // futures to be awaited.
-        let entries = parts_for_event(&self.config, &desc)
-            .map(|part| {
-                let file_index =
-                    self.open_or_create_cached(part.file_num)?;
-                let sqe =
-                    create_rw_sqe(self, op, file_index, &part, &desc);
-                let entry = self.submitter.submit_entry(sqe)?;
+        let mut entries: SmallVec<[(Part, ChunkRef, Waiter); 8]> =
+            SmallVec::new();
+
+        for part in parts_for_event(&self.config, &desc) {
+            let chunk = self.open_or_create_cached(part.file_num).await?;
+            let file_index = chunk.index();
+            self.metrics.chunk_access(part.file_num, op);
+
+            let entry = match &self.merger {
+                Some(merger) => {
+                    let segment = Segment {
+                        op,
+                        file_index,
+                        offset: part.start_sector << 9,
+                        buf: self.bufs.borrow_mut().get_buf_with_offsets(
+                            self.slot,
+                            part.buf_offset,
+                            0,
+                        ),
+                        len: part.nr_sectors << 9,
+                        rw_flags: fua_flags(&desc),
+                    };
+
+                    merger.submit(&mut self.submitter, segment)
+                }
+                None => {
+                    let sqe =
+                        create_rw_sqe(self, op, file_index, &part, &desc);
+                    self.submitter.submit_polled_entry(
+                        sqe,
+                        self.io_timeout.as_deref(),
+                    )?
+                }
+            };
 
-                Ok((part, file_index, entry))
-            })
-            .collect::<Result<SmallVec<[(Part, u32, Waiter); 8]>>>()?;
+            entries.push((part, chunk, entry));
+        }
 
         debug_assert!(!entries.spilled());
 
@@ -168,11 +307,16 @@ impl Task {
 
         // Go over each future again to make sure it finished successfully.
         for entry in entries {
-            let (part, file_index, mut fut) = entry;
+            let (part, chunk, mut fut) = entry;
+            let file_index = chunk.index();
             let mut current = 0;
+            let mut attempt = 0;
 
             loop {
-                let result = fut.await;
+                let offset = (part.start_sector << 9) + current as u64;
+                let result = self.wait_for_chunk(fut).await;
+                let result =
+                    self.check_timeout(result, part.file_num, offset);
 
                 debug_assert_ne!(result, 0);
 
@@ -186,17 +330,23 @@ impl Task {
                     }
                 }
 
-                // If there's an error other than EINTR, bail out and propagate
+                // If the error isn't worth retrying, bail out and propagate
                 // the error upstream.
-                if result < 0 && result != -libc::EINTR {
+                if result < 0 && !self.retry(result, &mut attempt).await? {
                     return Ok(result);
                 }
 
                 // A short. Try again with adjusted offset.
+                if result > 0 {
+                    self.metrics.short_retry(self.queue_id);
+                }
                 let sqe = create_rw_sqe_with_offset(
                     self, op, file_index, &part, &desc, current,
                 );
-                fut = self.submitter.submit_entry(sqe)?;
+                fut = self.submitter.submit_polled_entry(
+                    sqe,
+                    self.io_timeout.as_deref(),
+                )?;
             }
         }
 
@@ -216,35 +366,45 @@ impl Task {
             self.queue_id, self.tag, desc
         );
 
-        let entries = parts_for_event(&self.config, &desc)
-            .map(|part| {
-                let file_index =
-                    self.open_or_create_cached(part.file_num)?;
-                let sqe = create_flush_sqe(file_index);
-                let entry = self.submitter.submit_entry(sqe)?;
+        let mut entries: SmallVec<[(u32, ChunkRef, Waiter); 8]> =
+            SmallVec::new();
 
-                Ok((file_index, entry))
-            })
-            .collect::<Result<SmallVec<[(u32, Waiter); 8]>>>()?;
+        for part in parts_for_event(&self.config, &desc) {
+            let chunk = self.open_or_create_cached(part.file_num).await?;
+            let file_index = chunk.index();
+            let sqe = create_flush_sqe(file_index);
+            let entry = self.submitter.submit_entry_with_timeout(
+                sqe,
+                self.io_timeout.as_deref(),
+            )?;
+
+            entries.push((part.file_num, chunk, entry));
+        }
 
         debug_assert!(!entries.spilled());
 
         for entry in entries {
-            let (file_index, mut fut) = entry;
+            let (file_num, chunk, mut fut) = entry;
+            let file_index = chunk.index();
+            let mut attempt = 0;
 
             loop {
-                let result = fut.await;
+                let result = self.wait_for_chunk(fut).await;
+                let result = self.check_timeout(result, file_num, 0);
 
                 if result == 0 {
                     break;
                 }
 
-                if result < 0 && result != -libc::EINTR {
+                if result < 0 && !self.retry(result, &mut attempt).await? {
                     return Ok(result);
                 }
 
                 let sqe = create_flush_sqe(file_index);
-                fut = self.submitter.submit_entry(sqe)?;
+                fut = self.submitter.submit_entry_with_timeout(
+                    sqe,
+                    self.io_timeout.as_deref(),
+                )?;
             }
         }
 
@@ -260,64 +420,201 @@ impl Task {
             self.queue_id, self.tag, desc
         );
 
-        let entries = parts_for_event(&self.config, &desc)
-            .map(|part| {
-                let file_index =
-                    self.open_or_create_cached(part.file_num)?;
-                let sqe =
-                    create_write_zeroes_sqe(file_index, &part, &desc);
-                let entry = self.submitter.submit_entry(sqe)?;
+        let mut entries: SmallVec<[(Part, ChunkRef, Waiter); 8]> =
+            SmallVec::new();
 
-                Ok((part, file_index, entry))
-            })
-            .collect::<Result<SmallVec<[(Part, u32, Waiter); 8]>>>()?;
+        for part in parts_for_event(&self.config, &desc) {
+            let chunk = self.open_or_create_cached(part.file_num).await?;
+            let file_index = chunk.index();
+            self.metrics.chunk_access(part.file_num, desc.op());
+            let sqe = create_write_zeroes_sqe(file_index, &part, &desc);
+            let entry = self.submitter.submit_entry_with_timeout(
+                sqe,
+                self.io_timeout.as_deref(),
+            )?;
+
+            entries.push((part, chunk, entry));
+        }
 
         debug_assert!(!entries.spilled());
 
         for entry in entries {
-            let (part, file_index, mut fut) = entry;
+            let (part, chunk, mut fut) = entry;
+            let file_index = chunk.index();
+            let mut attempt = 0;
 
             loop {
-                let result = fut.await;
+                let result = self.wait_for_chunk(fut).await;
+                let result = self.check_timeout(
+                    result,
+                    part.file_num,
+                    part.start_sector << 9,
+                );
 
                 if result == 0 {
                     break;
                 }
 
-                if result < 0 && result != -libc::EINTR {
+                if result < 0 && !self.retry(result, &mut attempt).await? {
                     return Ok(result);
                 }
 
                 let sqe =
                     create_write_zeroes_sqe(file_index, &part, &desc);
-                fut = self.submitter.submit_entry(sqe)?;
+                fut = self.submitter.submit_entry_with_timeout(
+                    sqe,
+                    self.io_timeout.as_deref(),
+                )?;
             }
         }
 
         Ok(0)
     }
 
+    /// Waits for an entry submitted to a chunk and accounts the time to
+    /// the backing store.
+    async fn wait_for_chunk(&mut self, fut: Waiter) -> i32 {
+        let started = Instant::now();
+        let result = fut.await;
+        self.backing +=
+            self.submitter.reaped().saturating_duration_since(started);
+        result
+    }
+
     async fn send_commit_fetch_req(&mut self, result: i32) -> Result<i32> {
         let sqe = create_fetch_req_commit_sqe(UBLKC_FD_IDX, self, result);
         Ok(self.submitter.submit_entry(sqe)?.await)
     }
 
-    fn open_or_create_cached(&mut self, file_index: u32) -> Result<u32> {
-        let mut file_indexes = self.file_indexes.borrow_mut();
+    /// Returns whether a failed operation should be submitted again. EINTR
+    /// is always retried right away, other errors as per the retry policy.
+    async fn retry(
+        &mut self,
+        result: i32,
+        attempt: &mut u32,
+    ) -> Result<bool> {
+        if result == -libc::EINTR {
+            return Ok(true);
+        }
+
+        if !self.retry.should_retry(result, *attempt) {
+            return Ok(false);
+        }
+
+        *attempt += 1;
+        self.metrics.retry(result);
+
+        warn!(
+            queue_id = self.queue_id, tag = self.tag;
+            "operation failed with {}, retrying ({}/{})",
+            Errno::from_raw(-result),
+            attempt,
+            self.retry.max_attempts()
+        );
+
+        self.retry.backoff(&mut self.submitter, *attempt).await?;
+
+        Ok(true)
+    }
+
+    /// An operation with a linked timeout which fired completes with
+    /// -ECANCELED. If the operation cannot be interrupted (e.g. a hard NFS
+    /// mount), it only completes once the backing store recovers.
+    fn check_timeout(
+        &self,
+        result: i32,
+        file_num: u32,
+        offset: u64,
+    ) -> i32 {
+        if result != -libc::ECANCELED || self.io_timeout.is_none() {
+            return result;
+        }
+
+        let chunk = self.opener.path(file_num);
+
+        error!(
+            queue_id = self.queue_id, tag = self.tag, chunk = file_num;
+            "timed out on chunk {} at offset {}", chunk.display(), offset
+        );
+
+        -libc::ETIMEDOUT
+    }
+
+    /// Returns the chunk registered with the ring, opening (or creating)
+    /// it first if no worker has it open yet. The chunk stays pinned until
+    /// the returned reference is dropped.
+    async fn open_or_create_cached(
+        &mut self,
+        file_num: u32,
+    ) -> Result<ChunkRef> {
+        loop {
+            if let Some(chunk) = ChunkCache::get(&self.chunks, file_num) {
+                return Ok(chunk);
+            }
+
+            // Another task is opening the chunk. Look again once it's done,
+            // the opening might have failed.
+            if !self.opener.is_opening(file_num) {
+                break;
+            }
+            self.opener.opened(file_num).await;
+        }
+
+        let opener = self.opener.clone();
+        let _opening = opener.begin(file_num);
 
-        if let Some(idx) = file_indexes.get(&file_index) {
-            return Ok(*idx);
+        let fd = opener
+            .acquire(&mut self.submitter, file_num)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to open chunk {}.",
+                    opener.path(file_num).display()
+                )
+            })?;
+
+        // There must be no await between the flush and the eviction.
+        self.flush_victim().await?;
+        let chunk = ChunkCache::insert(&self.chunks, file_num)?;
+
+        // Replaces the evicted chunk's file if there's one.
+        let result =
+            self.submitter.register_files_update(chunk.index(), &[fd]);
+
+        if let Err(err) = result {
+            drop(chunk);
+            self.chunks.borrow_mut().remove(file_num);
+            return Err(err)
+                .context("Failed to register more file descriptors.");
         }
 
-        let file = open_or_create_chunk(&self.config, file_index)?;
-        let idx = (file_indexes.len() + 1) as u32;
+        Ok(chunk)
+    }
 
-        self.submitter
-            .register_files_update(idx, &[file.as_raw_fd()])
-            .context("Failed to register more file descriptors.")?;
-        file_indexes.insert(file_index, idx);
+    /// Flushes the chunk which is to be evicted to make room for another
+    /// one so that the writes to it are durable once it's closed. Flushing
+    /// it through the ring keeps the worker serving other requests.
+    async fn flush_victim(&mut self) -> Result<()> {
+        // The victim might be used while it's being flushed, or it might
+        // be evicted by another task and replaced with a new victim.
+        loop {
+            let victim = self.chunks.borrow().unflushed_victim();
+            let Some(victim) = victim else {
+                return Ok(());
+            };
+
+            let sqe = create_flush_sqe(victim.index);
+            let result = self.submitter.submit_entry(sqe)?.await;
+
+            if result < 0 {
+                error!(
+                    "Failed to sync chunk {} err={}",
+                    victim.chunk, result
+                );
+            }
 
-        Ok(idx)
+            self.chunks.borrow_mut().flushed(victim);
+        }
     }
 
     #[inline(always)]
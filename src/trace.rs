@@ -0,0 +1,403 @@
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+
+use crate::bindings::ublksrv_io_desc;
+use crate::bindings_ext::op_name;
+
+const MAGIC: &[u8; 8] = b"BLKCTRC1";
+const HEADER_SIZE: u64 = 64;
+pub const RECORD_SIZE: u64 = 40;
+
+/// Workers hand their records over in batches of this many, or after this
+/// long, whichever comes first.
+const BATCH: usize = 256;
+const BATCH_AGE: Duration = Duration::from_secs(1);
+
+/// A request as the device asked for it and how it went.
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// When the request arrived, relative to the start of the trace.
+    pub time: Duration,
+    pub latency: Duration,
+    pub start_sector: u64,
+    pub nr_sectors: u32,
+    pub op_flags: u32,
+    pub result: i32,
+    pub queue_id: u16,
+    pub tag: u16,
+}
+
+impl Record {
+    pub fn op(&self) -> u32 {
+        self.op_flags & 0xff
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.op_flags >> 8
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(
+            &(self.time.as_nanos() as u64).to_le_bytes(),
+        );
+        buf.extend_from_slice(
+            &(self.latency.as_nanos() as u64).to_le_bytes(),
+        );
+        buf.extend_from_slice(&self.start_sector.to_le_bytes());
+        buf.extend_from_slice(&self.nr_sectors.to_le_bytes());
+        buf.extend_from_slice(&self.op_flags.to_le_bytes());
+        buf.extend_from_slice(&self.result.to_le_bytes());
+        buf.extend_from_slice(&self.queue_id.to_le_bytes());
+        buf.extend_from_slice(&self.tag.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let u64_at = |at: usize| {
+            u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+        };
+        let u32_at = |at: usize| {
+            u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+        };
+        let u16_at = |at: usize| {
+            u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+        };
+
+        Self {
+            time: Duration::from_nanos(u64_at(0)),
+            latency: Duration::from_nanos(u64_at(8)),
+            start_sector: u64_at(16),
+            nr_sectors: u32_at(24),
+            op_flags: u32_at(28),
+            result: u32_at(32) as i32,
+            queue_id: u16_at(36),
+            tag: u16_at(38),
+        }
+    }
+}
+
+/// The header of a trace file, followed by `capacity` records. The file is
+/// a ring, once it's full the oldest records are overwritten.
+struct Header {
+    capacity: u64,
+
+    /// The number of records written so far, including overwritten ones.
+    written: u64,
+
+    /// When the trace started, in nanoseconds since the Unix epoch.
+    started: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut buf = [0u8; HEADER_SIZE as usize];
+        buf[0..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+        buf[16..24].copy_from_slice(&self.capacity.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.written.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.started.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; HEADER_SIZE as usize]) -> Result<Self> {
+        let u64_at = |at: usize| {
+            u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+        };
+
+        if &buf[0..8] != MAGIC {
+            bail!("Not a trace file.");
+        }
+
+        let record_size =
+            u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if record_size as u64 != RECORD_SIZE {
+            bail!("Unsupported record size {}.", record_size);
+        }
+
+        Ok(Self {
+            capacity: u64_at(16),
+            written: u64_at(24),
+            started: u64_at(32),
+        })
+    }
+}
+
+/// A trace file shared by all workers. The records are written by a
+/// background thread.
+pub struct Trace {
+    sender: Sender<Vec<Record>>,
+    epoch: Instant,
+}
+
+/// Finishes writing the trace once all workers are done with it.
+pub struct TraceWriter {
+    thread: JoinHandle<()>,
+}
+
+impl Trace {
+    /// Creates (or truncates) the file, which holds at most `size` bytes
+    /// worth of records.
+    pub fn create(
+        path: &Path,
+        size: u64,
+    ) -> Result<(Arc<Self>, TraceWriter)> {
+        let capacity = size.saturating_sub(HEADER_SIZE) / RECORD_SIZE;
+        if capacity == 0 {
+            bail!("The trace size is too small.");
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| {
+                format!("Failed to create the trace at {}", path.display())
+            })?;
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        let header = Header {
+            capacity,
+            written: 0,
+            started,
+        };
+        file.write_all_at(&header.encode(), 0)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("blkchnkr-trace".into())
+            .spawn(move || write_records(file, header, receiver))
+            .context("Failed to start the trace writer.")?;
+
+        let trace = Arc::new(Self {
+            sender,
+            epoch: Instant::now(),
+        });
+
+        Ok((trace, TraceWriter { thread }))
+    }
+}
+
+impl TraceWriter {
+    /// Waits until all records have been written. The workers must have
+    /// dropped their references to the trace by now.
+    pub fn finish(self, trace: Arc<Trace>) {
+        drop(trace);
+        _ = self.thread.join();
+    }
+}
+
+fn write_records(
+    file: File,
+    mut header: Header,
+    receiver: Receiver<Vec<Record>>,
+) {
+    let mut buf = Vec::with_capacity(BATCH * RECORD_SIZE as usize);
+
+    for records in receiver {
+        let mut records = &records[..];
+
+        // A batch might wrap around the end of the file.
+        while !records.is_empty() {
+            let idx = header.written % header.capacity;
+            let n = records.len().min((header.capacity - idx) as usize);
+
+            buf.clear();
+            records[..n]
+                .iter()
+                .for_each(|record| record.encode(&mut buf));
+
+            let offset = HEADER_SIZE + idx * RECORD_SIZE;
+            if let Err(err) = file.write_all_at(&buf, offset) {
+                error!("Failed to write the trace. Err: {}", err);
+                return;
+            }
+
+            header.written += n as u64;
+            records = &records[n..];
+        }
+
+        if let Err(err) = file.write_all_at(&header.encode(), 0) {
+            error!("Failed to write the trace. Err: {}", err);
+            return;
+        }
+    }
+
+    info!("Traced {} requests", header.written);
+}
+
+/// Collects the records of a worker and hands them over in batches.
+pub struct TraceBuffer {
+    trace: Arc<Trace>,
+    records: RefCell<Vec<Record>>,
+    flushed: Cell<Instant>,
+}
+
+impl TraceBuffer {
+    pub fn new(trace: Arc<Trace>) -> Self {
+        Self {
+            trace,
+            records: RefCell::new(Vec::with_capacity(BATCH)),
+            flushed: Cell::new(Instant::now()),
+        }
+    }
+
+    pub fn record(
+        &self,
+        queue_id: u16,
+        tag: u16,
+        desc: &ublksrv_io_desc,
+        fetched: Instant,
+        result: i32,
+    ) {
+        let record = Record {
+            time: fetched.saturating_duration_since(self.trace.epoch),
+            latency: fetched.elapsed(),
+            start_sector: desc.start_sector,
+            nr_sectors: unsafe { desc.__bindgen_anon_1.nr_sectors },
+            op_flags: desc.op_flags,
+            result,
+            queue_id,
+            tag,
+        };
+
+        let full = {
+            let mut records = self.records.borrow_mut();
+            records.push(record);
+            records.len() >= BATCH
+        };
+
+        if full || self.flushed.get().elapsed() >= BATCH_AGE {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&self) {
+        let records = self.records.replace(Vec::with_capacity(BATCH));
+        self.flushed.set(Instant::now());
+
+        if !records.is_empty() {
+            // The writer only goes away if it failed, which it reported.
+            _ = self.trace.sender.send(records);
+        }
+    }
+}
+
+impl Drop for TraceBuffer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Reads the records of a trace file, oldest first.
+pub fn read_trace(path: &Path) -> Result<(SystemTime, Vec<Record>)> {
+    let file = File::open(path).with_context(|| {
+        format!("Failed to open the trace at {}", path.display())
+    })?;
+
+    let mut buf = [0u8; HEADER_SIZE as usize];
+    file.read_exact_at(&mut buf, 0)?;
+    let header = Header::decode(&buf)?;
+
+    let count = header.written.min(header.capacity);
+    let first = if header.written > header.capacity {
+        header.written % header.capacity
+    } else {
+        0
+    };
+
+    let mut data = vec![0u8; (count * RECORD_SIZE) as usize];
+    file.read_exact_at(&mut data, HEADER_SIZE)
+        .context("The trace is truncated.")?;
+
+    let mut records: Vec<Record> = data
+        .chunks_exact(RECORD_SIZE as usize)
+        .map(Record::decode)
+        .collect();
+    records.rotate_left(first as usize);
+
+    let started = UNIX_EPOCH + Duration::from_nanos(header.started);
+    Ok((started, records))
+}
+
+/// Prints the records of the trace as text or CSV.
+pub fn dump(path: &Path, csv: bool, out: &mut impl Write) -> Result<()> {
+    let (_, records) = read_trace(path)?;
+
+    if csv {
+        writeln!(
+            out,
+            "time_ns,queue_id,tag,op,flags,start_sector,nr_sectors,\
+            result,latency_ns"
+        )?;
+    } else {
+        writeln!(
+            out,
+            "{:>14} {:>5} {:>5} {:<12} {:>6} {:>14} {:>8} {:>6} {:>12}",
+            "time (us)",
+            "queue",
+            "tag",
+            "op",
+            "flags",
+            "sector",
+            "sectors",
+            "result",
+            "latency (us)"
+        )?;
+    }
+
+    for record in &records {
+        let result = if csv {
+            writeln!(
+                out,
+                "{},{},{},{},{:#x},{},{},{},{}",
+                record.time.as_nanos(),
+                record.queue_id,
+                record.tag,
+                op_name(record.op()),
+                record.flags(),
+                record.start_sector,
+                record.nr_sectors,
+                record.result,
+                record.latency.as_nanos()
+            )
+        } else {
+            writeln!(
+                out,
+                "{:>14.1} {:>5} {:>5} {:<12} {:>#6x} {:>14} {:>8} {:>6} \
+                {:>12.1}",
+                record.time.as_nanos() as f64 / 1000.0,
+                record.queue_id,
+                record.tag,
+                op_name(record.op()),
+                record.flags(),
+                record.start_sector,
+                record.nr_sectors,
+                record.result,
+                record.latency.as_nanos() as f64 / 1000.0
+            )
+        };
+
+        // Stop quietly when piped into e.g. head.
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                return Ok(());
+            }
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
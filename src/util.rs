@@ -1,12 +1,18 @@
 use std::{
     fs::{self, File, OpenOptions, create_dir_all},
     io,
-    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileTypeExt, OpenOptionsExt},
+    },
     path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, anyhow, bail};
 use nix::{
+    errno::Errno,
     libc,
     unistd::{self, SysconfVar},
 };
@@ -21,6 +27,10 @@ pub fn page_size() -> Result<usize> {
         .map(|s| s as usize)
 }
 
+pub fn ublkc_path(dev_id: u32) -> String {
+    format!("/dev/ublkc{}", dev_id)
+}
+
 pub fn set_fsids(config: &Config) {
     if let Some(fsuid) = config.fsuid {
         unistd::setfsuid(fsuid.into());
@@ -31,8 +41,34 @@ pub fn set_fsids(config: &Config) {
     }
 }
 
-// This is executed once per chunk per thread. All operations should be
-// idempotent.
+/// Switches the filesystem ids back to the effective ids, e.g. to reopen
+/// devices after set_fsids.
+pub fn reset_fsids() {
+    unistd::setfsuid(unistd::geteuid());
+    unistd::setfsgid(unistd::getegid());
+}
+
+/// Polls the condition until it's true or the timeout runs out.
+pub fn wait_until(
+    timeout: Duration,
+    mut cond: impl FnMut() -> bool,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    cond()
+}
+
+// The blocking fallback for kernels which can't open chunks through the
+// ring. Workers might race to open the same chunk. All operations should
+// be idempotent.
 pub fn open_or_create_chunk(
     config: &Config,
     file_index: u32,
@@ -54,19 +90,51 @@ pub fn open_or_create_chunk(
         .open(filepath)
         .context("Failed to open/create chunk.")?;
 
-    ftruncate(config, &file)?;
+    allocate(config, &file)?;
 
     Ok(file)
 }
 
-fn build_filepath(config: &Config, file_index: u32) -> Result<PathBuf> {
-    let mut path = fs::canonicalize(&config.repository)?;
+pub fn build_filepath(
+    config: &Config,
+    file_index: u32,
+) -> Result<PathBuf> {
+    let mut chunks_dir = fs::canonicalize(&config.repository)?;
+    chunks_dir.push("chunks");
+
+    Ok(chunk_path(&chunks_dir, file_index))
+}
+
+/// Chunks are spread over 256 subdirectories of the chunks directory.
+pub fn chunk_path(chunks_dir: &Path, file_index: u32) -> PathBuf {
+    let mut path = chunks_dir.to_path_buf();
 
-    path.push("chunks");
     path.push(format!("{:02x}", file_index % 256));
     path.push(file_index.to_string());
 
-    Ok(path)
+    path
+}
+
+/// Syncs the file system the chunks live on, which covers the chunks
+/// still open as well as the ones closed without a sync and the
+/// directories the chunks were created in.
+pub fn sync_chunks(config: &Config) -> Result<()> {
+    let mut chunks = config.repository.clone();
+    chunks.push("chunks");
+
+    let dir = File::open(&chunks)?;
+    unistd::syncfs(&dir)
+        .with_context(|| format!("Failed to sync {}", chunks.display()))
+}
+
+/// Removes a socket left over from a previous run. Anything but a socket
+/// is left alone, the path may come from the config.
+pub fn remove_stale_socket(path: &Path) {
+    if fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_type().is_socket())
+    {
+        _ = fs::remove_file(path);
+    }
 }
 
 fn mkdir(subdir: &Path) -> Result<()> {
@@ -75,6 +143,37 @@ fn mkdir(subdir: &Path) -> Result<()> {
     })
 }
 
+/// Sizes the chunk as per the allocation policy.
+pub fn allocate(config: &Config, file: &File) -> Result<()> {
+    if !config.allocation().preallocate() {
+        return ftruncate(config, file);
+    }
+
+    match fallocate(config, file) {
+        Ok(()) => Ok(()),
+        Err(Errno::EOPNOTSUPP) => ftruncate(config, file),
+        Err(err) => bail!("fallocate failed: {}", err),
+    }
+}
+
+fn fallocate(config: &Config, file: &File) -> Result<(), Errno> {
+    loop {
+        let res = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                0,
+                0,
+                config.chunk_size as i64,
+            )
+        };
+
+        match Errno::result(res) {
+            Err(Errno::EINTR) => continue,
+            res => return res.map(drop),
+        }
+    }
+}
+
 fn ftruncate(config: &Config, file: &File) -> Result<()> {
     loop {
         let res = unsafe {
@@ -0,0 +1,96 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use io_uring::opcode::Timeout;
+use io_uring::types::Timespec;
+
+use crate::bindings::ublksrv_io_desc;
+use crate::bindings_ext::op_name;
+use crate::io_worker::Slot;
+use crate::runtime::Submitter;
+
+struct Request {
+    started: Instant,
+    op: u32,
+    start_sector: u64,
+    reported: bool,
+}
+
+/// Requests a worker is currently handling, indexed by slot.
+pub struct InFlight {
+    requests: Vec<Option<Request>>,
+}
+
+impl InFlight {
+    pub fn new(nr_slots: usize) -> Self {
+        let mut requests = Vec::with_capacity(nr_slots);
+        requests.resize_with(nr_slots, || None);
+
+        Self { requests }
+    }
+
+    pub fn start(&mut self, slot: u16, desc: &ublksrv_io_desc) {
+        self.requests[slot as usize] = Some(Request {
+            started: Instant::now(),
+            op: desc.op(),
+            start_sector: desc.start_sector,
+            reported: false,
+        });
+    }
+
+    /// Returns how long the request took if it has been reported as hung.
+    pub fn finish(&mut self, slot: u16) -> Option<Duration> {
+        let request = self.requests[slot as usize].take()?;
+        request.reported.then(|| request.started.elapsed())
+    }
+}
+
+/// Periodically reports requests which have been in flight for longer than
+/// the threshold. Each request is reported once.
+pub async fn watchdog(
+    mut submitter: Submitter,
+    slots: Box<[Slot]>,
+    inflight: Rc<RefCell<InFlight>>,
+    threshold: Duration,
+) {
+    let interval = Timespec::new().sec(1);
+
+    loop {
+        match submitter.submit_entry(Timeout::new(&interval).build()) {
+            Ok(waiter) => _ = waiter.await,
+            Err(err) => {
+                error!("Failed to arm the watchdog timer err={}", err);
+                return;
+            }
+        }
+
+        let mut inflight = inflight.borrow_mut();
+
+        for (slot, request) in inflight.requests.iter_mut().enumerate() {
+            let Some(request) = request else {
+                continue;
+            };
+
+            let elapsed = request.started.elapsed();
+            if request.reported || elapsed < threshold {
+                continue;
+            }
+
+            let Slot { queue_id, tag } = slots[slot];
+            warn!(
+                queue_id = queue_id,
+                tag = tag,
+                op = op_name(request.op),
+                sector = request.start_sector;
+                "{} request at sector {} has been in flight for {}s. The \
+                backing store might be stalled.",
+                op_name(request.op),
+                request.start_sector,
+                elapsed.as_secs()
+            );
+
+            request.reported = true;
+        }
+    }
+}
@@ -0,0 +1,56 @@
+#!/usr/bin/bash
+
+set -ue
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+. ./common.sh
+
+# Prints the checksums of all files in the directory.
+checksum_dir() (
+  cd "$1"
+  find . -type f -print0 | sort -z | xargs -0 sha256sum
+)
+
+# Checks that a quiesced device can be copied consistently and resumed.
+test_05_quiesce() (
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M
+
+  start_server "${tmp_dir}/repo"
+  local pid=$!
+
+  # Create a file system
+  mkfs.xfs -q "/dev/ublkb${dev_id}"
+
+  # Mount the file system and put some data in it.
+  mount --mkdir "/dev/ublkb${dev_id}" "${tmp_dir}/mount"
+  head -c 150M /dev/random > "${tmp_dir}/mount/random_file"
+  sync
+
+  # Quiesce the device and take a copy of the chunks.
+  ../target/debug/blkchnkr quiesce -r "${tmp_dir}/repo"
+  cp -r "${tmp_dir}/repo/chunks" "${tmp_dir}/chunks_copy"
+
+  # Nothing changes the chunks while the device is quiesced.
+  if [ "$(checksum_dir "${tmp_dir}/repo/chunks")" != \
+    "$(checksum_dir "${tmp_dir}/chunks_copy")" ]; then
+    echo "the copy differs from the quiesced chunks"
+    exit 1
+  fi
+
+  ../target/debug/blkchnkr resume -r "${tmp_dir}/repo"
+
+  # The device keeps working after the resume.
+  head -c 50M /dev/random > "${tmp_dir}/mount/random_file2"
+  sync
+
+  # Clean up
+  umount -l "${tmp_dir}/mount"
+  kill ${pid}
+  rm -rf "${tmp_dir}"
+)
+
+run_test test_05_quiesce
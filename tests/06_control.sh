@@ -0,0 +1,50 @@
+#!/usr/bin/bash
+
+set -ue
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+. ./common.sh
+
+# Checks that a running server can be controlled over its control socket.
+test_06_control() (
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+  local ctl="../target/debug/blkchnkr control -r ${tmp_dir}/repo"
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M
+
+  start_server "${tmp_dir}/repo"
+  local pid=$!
+
+  ${ctl} status | grep -q "state: live"
+
+  # Write some data so that there are chunks to flush and close.
+  dd if=/dev/random of="/dev/ublkb${dev_id}" bs=1M count=200 \
+    oflag=direct status=none
+
+  ${ctl} flush | grep -q "Synced"
+  ${ctl} close-idle-chunks | grep -q "Closed"
+  ${ctl} set-log-level warn
+  ../target/debug/blkchnkr stats -r "${tmp_dir}/repo" | grep -q "write total"
+
+  # Unknown commands are rejected.
+  if ${ctl} frobnicate 2>/dev/null; then
+    echo "accepted an unknown command"
+    exit 1
+  fi
+
+  # The server shuts down and removes the socket.
+  ${ctl} stop
+  wait ${pid}
+  test ! -e "${tmp_dir}/repo/control.sock"
+
+  # The chunk accesses are persisted on the way out.
+  ../target/debug/blkchnkr heatmap -r "${tmp_dir}/repo" \
+    | grep -q "^[0-9]* of 16 chunks accessed"
+
+  # Clean up
+  rm -rf "${tmp_dir}"
+)
+
+run_test test_06_control
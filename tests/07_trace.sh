@@ -0,0 +1,73 @@
+#!/usr/bin/bash
+
+set -ue
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+. ./common.sh
+
+# Checks that requests are recorded to the trace and can be dumped and
+# replayed.
+test_07_trace() (
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M
+
+  # Small enough for the ring to wrap around.
+  start_server "${tmp_dir}/repo" --trace "${tmp_dir}/trace" \
+    --trace-size 1M
+  local pid=$!
+
+  dd if=/dev/random of="/dev/ublkb${dev_id}" bs=4k count=50000 \
+    oflag=direct status=none
+
+  kill -SIGINT ${pid}
+  wait ${pid}
+
+  # The header and a full ring of 40 byte records.
+  test "$(stat -c %s "${tmp_dir}/trace")" -eq $((64 + 26212 * 40))
+
+  ../target/debug/blkchnkr trace dump "${tmp_dir}/trace" | grep -q "write"
+
+  local writes=$(../target/debug/blkchnkr trace dump --csv \
+    "${tmp_dir}/trace" | grep -c ",write,")
+  test "${writes}" -gt 20000
+
+  # The trace can be replayed against the repository once the server is
+  # gone.
+  ../target/debug/blkchnkr replay -r "${tmp_dir}/repo" \
+    --trace "${tmp_dir}/trace" --fast | grep -q "(0 failed, 0 skipped)"
+
+  # Clean up
+  rm -rf "${tmp_dir}"
+)
+
+# Checks that a trace of concurrent requests, which are recorded out of
+# order, can be replayed with the original timing.
+test_07_trace_concurrent() (
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M --threads 2
+
+  start_server "${tmp_dir}/repo" --trace "${tmp_dir}/trace"
+  local pid=$!
+
+  fio --name 07_trace --filename="/dev/ublkb${dev_id}" --rw=randrw \
+    --bs=4k --direct=1 --ioengine=libaio --iodepth=32 --numjobs=2 \
+    --time_based --runtime=3s > /dev/null
+
+  kill -SIGINT ${pid}
+  wait ${pid}
+
+  ../target/debug/blkchnkr replay -r "${tmp_dir}/repo" \
+    --trace "${tmp_dir}/trace" | grep -q "(0 failed, 0 skipped)"
+
+  # Clean up
+  rm -rf "${tmp_dir}"
+)
+
+run_test test_07_trace
+run_test test_07_trace_concurrent
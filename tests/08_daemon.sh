@@ -0,0 +1,43 @@
+#!/usr/bin/bash
+
+set -ue
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+. ./common.sh
+
+# Checks that the server can run in the background.
+test_08_daemon() (
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M
+
+  # Returns only once the device is up.
+  ../target/debug/blkchnkr start -r "${tmp_dir}/repo" --daemon \
+    --log-file "${tmp_dir}/log"
+  test -b "/dev/ublkb${dev_id}"
+  local pid=$(cat "${tmp_dir}/repo/blkchnkr.pid")
+  kill -0 "${pid}"
+
+  # A second server is refused.
+  if ../target/debug/blkchnkr start -r "${tmp_dir}/repo" --daemon \
+    --log-file "${tmp_dir}/log" 2>/dev/null; then
+    echo "started a second server"
+    exit 1
+  fi
+
+  ../target/debug/blkchnkr control -r "${tmp_dir}/repo" stop
+  while kill -0 "${pid}" 2>/dev/null; do
+    sleep 0.1
+  done
+
+  test ! -e "${tmp_dir}/repo/blkchnkr.pid"
+  grep -q "Ready!" "${tmp_dir}/log"
+  grep -q "Drained in .*, synced" "${tmp_dir}/log"
+
+  # Clean up
+  rm -rf "${tmp_dir}"
+)
+
+run_test test_08_daemon
@@ -0,0 +1,50 @@
+#!/usr/bin/bash
+
+set -ue
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+. ./common.sh
+
+# Checks that workers serving different queues are pinned to the CPUs of
+# their own queue.
+test_09_affinity() (
+  if [ "$(nproc)" -lt 2 ]; then
+    echo "skipping, needs at least 2 CPUs"
+    return
+  fi
+
+  local dev_id=$(random_dev_id)
+  local tmp_dir=$(create_tmp_dir)
+
+  ../target/debug/blkchnkr init --dev-id "${dev_id}" -r "${tmp_dir}/repo" \
+    --size 1G --chunk-size 64M --threads 2
+
+  start_server "${tmp_dir}/repo"
+  local pid=$!
+
+  # Collect the CPUs of every worker thread.
+  local masks=()
+  for task in /proc/${pid}/task/*; do
+    if grep -q "worker" "${task}/comm"; then
+      masks+=("$(grep Cpus_allowed_list "${task}/status" | cut -f 2)")
+    fi
+  done
+
+  if [ "${#masks[@]}" -ne 2 ]; then
+    echo "expected 2 workers, got ${#masks[@]}"
+    exit 1
+  fi
+
+  if [ "${masks[0]}" == "${masks[1]}" ]; then
+    echo "workers on different queues share CPUs ${masks[0]}"
+    exit 1
+  fi
+
+  kill ${pid}
+  wait ${pid}
+
+  # Clean up
+  rm -rf "${tmp_dir}"
+)
+
+run_test test_09_affinity
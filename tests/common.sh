@@ -17,8 +17,9 @@ create_tmp_dir() {
 
 start_server() {
   local repo=$1
+  shift
 
-  ../target/debug/blkchnkr start -r "${repo}" &
+  ../target/debug/blkchnkr start -r "${repo}" "$@" &
   local pid=$!
 
   sleep 0.2
@@ -8,5 +8,10 @@ cd "$(dirname "${BASH_SOURCE[0]}")"
 ./02_fio.sh
 ./03_brtfs.sh
 ./04_recovery.sh
+./05_quiesce.sh
+./06_control.sh
+./07_trace.sh
+./08_daemon.sh
+./09_affinity.sh
 
 echo "PASS"